@@ -1,10 +1,20 @@
-use pneumatic;
+use anyhow::{anyhow, bail, Context};
+use futures::StreamExt;
 use pneumatic::{
-    config::ServerConfig,
-    transfer::{DiscoveryMessage, FileSystem, TransferPlan},
+    client::Client,
+    config::{ConnectionConfig, ServerConfig},
+    protocol::{ClientMessage, ServerResponse},
+    receiver::{create_directory, ConflictPolicy, PartialFile},
+    server::{DiskFileSystem, Server},
+    transfer::{
+        discovery_stream, monitor_stalls, size_histogram, CachingFileSystem, DiscoveryCache,
+        DiscoveryMessage, DiscoveryProgress, FileMetadata, FileSystem, StdFilesystem, TransferItem,
+        TransferPlan,
+    },
 };
 use std::{
-    path::PathBuf,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    path::{Path, PathBuf},
     sync::Arc,
     time::{self, Duration},
 };
@@ -14,19 +24,50 @@ use time::Instant;
 async fn main() {
     let args: Vec<String> = std::env::args().collect();
 
+    if args.get(1).map(String::as_str) == Some("--self-test") {
+        let passed = self_test().await;
+        std::process::exit(if passed { 0 } else { 1 });
+    }
+
     let root_path = args.get(1).expect("Expected path as the first argument");
     let root_path = PathBuf::from(root_path);
 
+    // Listings of unchanged directories are reused from the previous run.
+    let cache_path = args.get(2).map(PathBuf::from);
+    let cache = match &cache_path {
+        Some(path) => DiscoveryCache::load(path).expect("Failed to load discovery cache"),
+        None => DiscoveryCache::default(),
+    };
+
+    let config = ServerConfig::default();
+
     let begin = time::Instant::now();
 
-    let fs = pneumatic::transfer::StdFilesystem::new(&root_path);
-    let fs_arc = Arc::new(fs);
+    let fs = StdFilesystem::new(&root_path);
+    let fs_arc = Arc::new(CachingFileSystem::new(fs, cache));
+    let cached_fs = fs_arc.clone();
 
     let (sender, mut receiver) = tokio::sync::mpsc::channel(16);
 
+    let progress = Arc::new(DiscoveryProgress::new());
+
+    tokio::spawn(monitor_stalls(
+        progress.clone(),
+        config.get_discovery_stall_warning(),
+        |snapshot| {
+            eprintln!(
+                "Warning: no directory finished in {}s ({} queued, {} being read, {} done)",
+                snapshot.since_last_completion.as_secs(),
+                snapshot.queue_depth,
+                snapshot.active_workers,
+                snapshot.completed_directories
+            );
+        },
+    ));
+
     let discover = tokio::spawn(async move {
         fs_arc
-            .discover_files_recursively(root_path, sender)
+            .discover_with_progress(root_path, sender, progress)
             .await
             .unwrap();
     });
@@ -34,6 +75,7 @@ async fn main() {
     let reporter = tokio::spawn(async move {
         let mut all_files = Vec::new();
 
+        // Discovery drops every sender when it finishes, which ends the loop.
         loop {
             match receiver.recv().await {
                 None => break,
@@ -60,5 +102,244 @@ async fn main() {
         took.as_millis()
     );
 
-    TransferPlan::create(all_files.unwrap(), &ServerConfig::default());
+    if let Some(path) = cache_path {
+        cached_fs
+            .cache()
+            .save(path)
+            .expect("Failed to save discovery cache");
+    }
+
+    let all_files = all_files.unwrap();
+
+    // Powers of ten, plus the configured thresholds to show what falls on
+    // either side of them.
+    let mut bounds: Vec<u64> = (3..=9).map(|exponent| 10u64.pow(exponent)).collect();
+    bounds.push(config.get_small_file_threshold());
+    bounds.push(config.get_large_file_threshold());
+    bounds.push(config.get_bundle_target_size());
+
+    println!("File sizes:");
+    for bucket in size_histogram(&all_files, &bounds).buckets {
+        let range = match bucket.max {
+            Some(max) => format!("{} - {}", bucket.min, max - 1),
+            None => format!("{} -", bucket.min),
+        };
+
+        println!(
+            "  {:>24} bytes: {} files, {} bytes",
+            range, bucket.count, bucket.total_bytes
+        );
+    }
+
+    let plan = TransferPlan::create(all_files, &config);
+    let small_files: usize = plan.bundles.iter().map(|bundle| bundle.files.len()).sum();
+
+    println!(
+        "Small files: {}\nSingle chunk files: {}\nLarge files: {}\nEmpty directories: {}",
+        small_files,
+        plan.single_chunk_files.len(),
+        plan.large_files.len(),
+        plan.directories.len()
+    );
+}
+
+/// Generates a small tree, serves it over loopback and downloads it into a
+/// second directory, checking that every file arrives intact. Returns whether
+/// the test passed.
+async fn self_test() -> bool {
+    let work_dir = std::env::temp_dir().join(format!("pneumatic-self-test-{}", std::process::id()));
+    let begin = Instant::now();
+
+    let result = run_self_test(&work_dir).await;
+    let _ = std::fs::remove_dir_all(&work_dir);
+
+    match result {
+        Ok(summary) => {
+            println!(
+                "Self-test passed: {} files ({} bytes) and {} empty directories verified in {}ms",
+                summary.files,
+                summary.bytes,
+                summary.directories,
+                begin.elapsed().as_millis()
+            );
+            true
+        }
+        Err(error) => {
+            println!("Self-test failed: {:#}", error);
+            false
+        }
+    }
+}
+
+struct SelfTestSummary {
+    files: usize,
+    bytes: u64,
+    directories: usize,
+}
+
+async fn run_self_test(work_dir: &Path) -> Result<SelfTestSummary, anyhow::Error> {
+    let source = work_dir.join("source");
+    let destination = work_dir.join("destination");
+
+    if work_dir.exists() {
+        std::fs::remove_dir_all(work_dir)?;
+    }
+
+    generate_tree(&source).context("Failed to generate the test tree")?;
+    std::fs::create_dir_all(&destination)?;
+
+    // Scaled down so that the small tree still has bundles, single chunk files
+    // and large files in it.
+    let config = ServerConfig {
+        small_file_threshold_bytes: Some(4 * 1024),
+        large_file_threshold_bytes: Some(256 * 1024),
+        bundle_target_size: Some(16 * 1024),
+        hash_during_discovery: Some(true),
+        ..ServerConfig::default()
+    };
+
+    let fs = Arc::new(StdFilesystem::from_config(&source, &config));
+    let (sender, receiver) = tokio::sync::mpsc::channel(16);
+    let discover = tokio::spawn(fs.discover_files_recursively(source.clone(), sender));
+    let files: Vec<FileMetadata> = discovery_stream(receiver).collect().await;
+    discover.await?.context("Discovery failed")?;
+
+    let plan = TransferPlan::create(files, &config);
+
+    let address = SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0);
+    let listener = Server::bind(SocketAddr::V4(address), &config)?;
+    let address = match listener.local_addr()? {
+        SocketAddr::V4(address) => address,
+        SocketAddr::V6(_) => unreachable!(),
+    };
+
+    let disk = DiskFileSystem::new(&source, &config)?;
+    let concurrency = config.get_transfer_concurrency() as usize;
+    let mtime_tolerance = config.get_mtime_tolerance();
+    let _server = Server::start_new(Box::new(disk), listener, config);
+
+    // One connection per transfer slot, so that the plan is executed with the
+    // configured concurrency.
+    let mut clients = Vec::with_capacity(concurrency);
+    for _ in 0..concurrency {
+        let mut client = Client::connect(address, &ConnectionConfig::default()).await?;
+        // A small window makes the large file go through flow control.
+        client.greet(Some(4)).await?;
+        clients.push(client);
+    }
+    let clients = tokio::sync::Mutex::new(clients);
+
+    let mut summary = SelfTestSummary {
+        files: 0,
+        bytes: 0,
+        directories: 0,
+    };
+
+    for directory in &plan.directories {
+        create_directory(&destination, directory).await?;
+        summary.directories += 1;
+    }
+
+    let transferred = std::sync::Mutex::new((0, 0));
+    let (clients, transferred, destination) = (&clients, &transferred, &destination);
+
+    plan.execute(concurrency, move |item| async move {
+        let files = match item {
+            TransferItem::Bundle(bundle) => bundle.files.iter().collect(),
+            TransferItem::File(file) => vec![file],
+        };
+
+        let mut client = clients.lock().await.pop().expect("No free client");
+
+        for file in files {
+            receive_file(&mut client, destination, file, mtime_tolerance)
+                .await
+                .with_context(|| format!("Failed to transfer {:?}", file.relative_path))?;
+
+            let mut transferred = transferred.lock().unwrap();
+            transferred.0 += 1;
+            transferred.1 += file.uncompressed_size;
+        }
+
+        clients.lock().await.push(client);
+        Ok::<_, anyhow::Error>(())
+    })
+    .await?;
+
+    let (files, bytes) = *transferred.lock().unwrap();
+    summary.files = files;
+    summary.bytes = bytes;
+
+    for client in clients.lock().await.iter_mut() {
+        client.send_message(ClientMessage::Disconnect).await?;
+    }
+
+    for directory in &plan.directories {
+        if !destination.join(&directory.relative_path).is_dir() {
+            bail!("Empty directory {:?} is missing", directory.relative_path);
+        }
+    }
+
+    Ok(summary)
+}
+
+/// Downloads `file` under `destination` and compares its hash with the one
+/// taken during discovery.
+async fn receive_file(
+    client: &mut Client,
+    destination: &Path,
+    file: &FileMetadata,
+    mtime_tolerance: Duration,
+) -> Result<(), anyhow::Error> {
+    let policy = ConflictPolicy::default();
+    let mut partial = PartialFile::create_with_policy(destination, file, policy, mtime_tolerance)
+        .await?
+        .ok_or_else(|| anyhow!("{:?} policy skipped the file", policy))?;
+
+    client.request_file(&file.relative_path).await?;
+
+    loop {
+        match client.receive_response().await? {
+            ServerResponse::FileChunk(chunk) => partial.write(&chunk).await?,
+            ServerResponse::FileEnd => break,
+            ServerResponse::Error(error) => bail!("The server refused the file: {:?}", error),
+            response => bail!("Unexpected response {:?}", response),
+        }
+    }
+
+    // Checked before the file is moved into place, so a corrupt file never
+    // shows up at its final path.
+    let expected_hash = file
+        .content_hash
+        .ok_or_else(|| anyhow!("Discovery didn't hash the file"))?;
+    partial
+        .commit(file.uncompressed_size, Some(expected_hash))
+        .await?;
+
+    Ok(())
+}
+
+/// Writes files of assorted sizes, some nested, and an empty directory.
+fn generate_tree(root: &Path) -> Result<(), std::io::Error> {
+    let content = |size: usize, seed: usize| -> Vec<u8> {
+        (0..size)
+            .map(|i| ((i * 31 + seed * 7) % 251) as u8)
+            .collect()
+    };
+
+    let mut files: Vec<(PathBuf, usize)> = (0..24)
+        .map(|i| (PathBuf::from(format!("notes/note-{}.txt", i)), i * 150))
+        .collect();
+    files.push((PathBuf::from("notes/deeper/nested.txt"), 3000));
+    files.push((PathBuf::from("data/medium-1.bin"), 20_000));
+    files.push((PathBuf::from("data/medium-2.bin"), 100_000));
+    files.push((PathBuf::from("data/large.bin"), 600_000));
+
+    for (seed, (path, size)) in files.iter().enumerate() {
+        let path = root.join(path);
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        std::fs::write(path, content(*size, seed))?;
+    }
+
+    std::fs::create_dir_all(root.join("empty"))
 }
@@ -1,36 +1,230 @@
-use crate::{networking::Connection, protocol::ClientMessage};
-use std::net::SocketAddrV4;
+use crate::{
+    config::ConnectionConfig,
+    crypto::{HandshakeError, StreamError},
+    networking::Connection,
+    protocol::{
+        CancelRequest, ClientMessage, CreditUpdate, FileRangeRequest, FileRequest, Greeting,
+        GreetingResponse, ReqRes, RequestId, ResumeOffset, ResumeRequest, ResumeResponse,
+        ResumptionToken, ServerResponse, PROTOCOL_VERSION,
+    },
+};
+use std::{net::SocketAddrV4, path::PathBuf};
 use tokio::net::TcpStream;
 
+/// The receive window agreed on in the greeting.
+struct FlowControl {
+    window: u32,
+    /// Chunks received since credit was last sent.
+    unacknowledged: u32,
+}
+
 pub struct Client {
     connection: Option<Connection>,
+    next_request_id: RequestId,
+    flow_control: Option<FlowControl>,
 }
 
 impl Client {
-    pub async fn connect(target: SocketAddrV4) -> Self {
+    pub async fn connect(
+        target: SocketAddrV4,
+        config: &ConnectionConfig,
+    ) -> Result<Self, HandshakeError> {
         println!("Client connecting to {}", target);
 
-        let stream = TcpStream::connect(target).await.unwrap();
+        let stream = TcpStream::connect(target).await?;
 
         println!("Client connected.");
 
-        let connection = Connection::new_encrypted(stream).await;
+        let connection = Connection::new_encrypted(stream, config).await?;
 
-        Client {
+        Ok(Client {
             connection: Some(connection),
-        }
+            next_request_id: 0,
+            flow_control: None,
+        })
     }
 
-    async fn send_message_stream(connection: &mut Connection, message: ClientMessage) {
-        connection.stream.send_bincode(&message).await;
+    /// Connects to the server to resume the session `token` was handed out
+    /// for, deriving the keys from the token instead of going through the key
+    /// exchange again. Fails with `HandshakeError::ResumptionRejected` if the
+    /// session has ended or expired. `resume` must still be called to pick
+    /// the session up.
+    pub async fn reconnect(
+        target: SocketAddrV4,
+        config: &ConnectionConfig,
+        token: &ResumptionToken,
+    ) -> Result<Self, HandshakeError> {
+        println!("Client reconnecting to {}", target);
+
+        let stream = TcpStream::connect(target).await?;
+        let connection = Connection::resume_encrypted(stream, config, token).await?;
+
+        Ok(Client {
+            connection: Some(connection),
+            next_request_id: 0,
+            flow_control: None,
+        })
     }
 
-    pub async fn send_message(&mut self, message: ClientMessage) {
+    async fn send_message_stream(
+        connection: &mut Connection,
+        message: ClientMessage,
+    ) -> Result<(), StreamError> {
+        connection.stream.send(&message).await?;
+        connection.stream.flush().await
+    }
+
+    pub async fn send_message(&mut self, message: ClientMessage) -> Result<(), StreamError> {
         match self.connection.as_mut() {
-            None => {}
+            None => Ok(()),
             Some(connection) => Self::send_message_stream(connection, message).await,
         }
     }
+
+    /// Greets the server, asking it to keep at most `receive_window` file
+    /// chunks in flight. If the server agrees, `receive_response` hands out
+    /// credit as chunks are received, so the server can't get further ahead
+    /// than that of what the caller has processed.
+    pub async fn greet(
+        &mut self,
+        receive_window: Option<u32>,
+    ) -> Result<GreetingResponse, StreamError> {
+        let greeting = Greeting {
+            protocol_version: PROTOCOL_VERSION,
+            receive_window,
+        };
+
+        let response = self.request(greeting).await?;
+
+        if let GreetingResponse::ProtocolOk {
+            receive_window: Some(window),
+            ..
+        } = &response
+        {
+            self.flow_control = Some(FlowControl {
+                window: *window,
+                unacknowledged: 0,
+            });
+        }
+
+        Ok(response)
+    }
+
+    /// Resumes the session `token` was handed out for, instead of greeting the
+    /// server. `received` tells how much of the file that was being received
+    /// actually arrived. The session's receive window is restored, so credit
+    /// is handed out like on the original connection.
+    ///
+    /// The client should normally come from `reconnect` with the same token.
+    pub async fn resume(
+        &mut self,
+        token: ResumptionToken,
+        received: Option<ResumeOffset>,
+    ) -> Result<ResumeResponse, StreamError> {
+        let response = self.request(ResumeRequest { token, received }).await?;
+
+        if let ResumeResponse::Resumed {
+            receive_window: Some(window),
+        } = &response
+        {
+            self.flow_control = Some(FlowControl {
+                window: *window,
+                unacknowledged: 0,
+            });
+        }
+
+        Ok(response)
+    }
+
+    /// Requests a file. The server responds with `FileChunk`s followed by
+    /// `FileEnd`, or with an `Error`. The returned id can be passed to
+    /// `cancel`.
+    pub async fn request_file(
+        &mut self,
+        relative_path: impl Into<PathBuf>,
+    ) -> Result<RequestId, StreamError> {
+        let request_id = self.next_request_id;
+        self.next_request_id += 1;
+
+        let request = FileRequest {
+            request_id,
+            relative_path: relative_path.into(),
+        };
+
+        self.send_message(request.into()).await?;
+        Ok(request_id)
+    }
+
+    /// Requests `length` bytes of a file starting at `offset`. The response is
+    /// the same as for `request_file`, but only covers the range, cut short at
+    /// the end of the file. An offset past the end is an `InvalidRange` error.
+    pub async fn request_file_range(
+        &mut self,
+        relative_path: impl Into<PathBuf>,
+        offset: u64,
+        length: u64,
+    ) -> Result<RequestId, StreamError> {
+        let request_id = self.next_request_id;
+        self.next_request_id += 1;
+
+        let request = FileRangeRequest {
+            request_id,
+            relative_path: relative_path.into(),
+            offset,
+            length,
+        };
+
+        self.send_message(request.into()).await?;
+        Ok(request_id)
+    }
+
+    /// Asks the server to stop sending a file. The server answers with
+    /// `Cancelled` or `AlreadyCompleted`; chunks sent before the cancellation
+    /// was handled may still arrive before that.
+    pub async fn cancel(&mut self, request_id: RequestId) -> Result<(), StreamError> {
+        self.send_message(CancelRequest { request_id }.into()).await
+    }
+
+    /// Closes the connection without telling the server, as if it had been
+    /// lost. The server keeps the session around for it to be resumed.
+    pub fn abort(mut self) {
+        self.connection.take();
+    }
+
+    pub async fn receive_response(&mut self) -> Result<ServerResponse, StreamError> {
+        let connection = self.connection.as_mut().expect("Client is not connected");
+
+        let mut buffer = Vec::new();
+        let response = connection.stream.receive(&mut buffer).await?;
+
+        if let (ServerResponse::FileChunk(_), Some(flow)) = (&response, &mut self.flow_control) {
+            flow.unacknowledged += 1;
+
+            // Acknowledging every chunk would double the number of frames, so
+            // credit is sent once half of the window has been used.
+            if flow.unacknowledged >= (flow.window / 2).max(1) {
+                let credit = CreditUpdate {
+                    frames: flow.unacknowledged,
+                };
+                flow.unacknowledged = 0;
+
+                Self::send_message_stream(connection, credit.into()).await?;
+            }
+        }
+
+        Ok(response)
+    }
+
+    pub async fn request<R: ReqRes + Into<ClientMessage>>(
+        &mut self,
+        request: R,
+    ) -> Result<R::Response, StreamError> {
+        let connection = self.connection.as_mut().expect("Client is not connected");
+        Self::send_message_stream(connection, request.into()).await?;
+
+        let mut buffer = Vec::new();
+        connection.stream.receive(&mut buffer).await
+    }
 }
 
 impl Drop for Client {
@@ -40,7 +234,8 @@ impl Drop for Client {
             Some(connection) => {
                 tokio::spawn(async move {
                     let mut connection = connection;
-                    Self::send_message_stream(&mut connection, ClientMessage::Disconnect).await
+                    let _ =
+                        Self::send_message_stream(&mut connection, ClientMessage::Disconnect).await;
                 });
             }
         }
@@ -0,0 +1,97 @@
+use async_trait::async_trait;
+use std::{
+    fmt::Debug,
+    future::Future,
+    time::{Duration, Instant},
+};
+use tokio::sync::watch;
+
+/// A source of time. Time-dependent parts of the server take one of these
+/// instead of using `Instant::now` and `tokio::time` directly, so that tests
+/// can use `MockClock` and skip ahead instead of waiting.
+#[async_trait]
+pub trait Clock: Debug + Send + Sync {
+    fn now(&self) -> Instant;
+
+    /// Completes once `now()` is at or past `deadline`.
+    async fn sleep_until(&self, deadline: Instant);
+
+    async fn sleep(&self, duration: Duration) {
+        self.sleep_until(self.now() + duration).await
+    }
+}
+
+/// The real clock, backed by tokio's timer.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TokioClock;
+
+#[async_trait]
+impl Clock for TokioClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    async fn sleep_until(&self, deadline: Instant) {
+        tokio::time::delay_until(deadline.into()).await
+    }
+}
+
+/// Returned by `timeout` when the duration elapses before the future completes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Elapsed;
+
+/// Like `tokio::time::timeout`, but measured with `clock`.
+pub async fn timeout<F: Future>(
+    clock: &dyn Clock,
+    duration: Duration,
+    future: F,
+) -> Result<F::Output, Elapsed> {
+    tokio::select! {
+        output = future => Ok(output),
+        _ = clock.sleep(duration) => Err(Elapsed),
+    }
+}
+
+/// A clock that only moves when `advance` is called. Sleepers wake as soon as
+/// the clock is advanced past their deadline.
+#[derive(Debug)]
+pub struct MockClock {
+    sender: watch::Sender<Instant>,
+    receiver: watch::Receiver<Instant>,
+}
+
+impl MockClock {
+    pub fn new() -> Self {
+        let (sender, receiver) = watch::channel(Instant::now());
+        MockClock { sender, receiver }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        let now = self.now() + duration;
+        // The clock holds a receiver itself, so this can't fail.
+        let _ = self.sender.broadcast(now);
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        *self.receiver.borrow()
+    }
+
+    async fn sleep_until(&self, deadline: Instant) {
+        let mut receiver = self.receiver.clone();
+
+        while *receiver.borrow() < deadline {
+            if receiver.recv().await.is_none() {
+                return;
+            }
+        }
+    }
+}
@@ -0,0 +1,153 @@
+use bincode::Options;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum CodecError {
+    #[error("bincode error: {0}")]
+    Bincode(#[from] bincode::Error),
+    #[cfg(feature = "msgpack")]
+    #[error("MessagePack encoding error: {0}")]
+    MessagePackEncode(#[from] rmp_serde::encode::Error),
+    #[cfg(feature = "msgpack")]
+    #[error("MessagePack decoding error: {0}")]
+    MessagePackDecode(#[from] rmp_serde::decode::Error),
+}
+
+/// Turns messages into the plaintext of a frame and back.
+pub trait Codec {
+    /// Encodes `object`, failing if it takes more than `limit` bytes.
+    fn encode<S: Serialize>(&self, object: &S, limit: usize) -> Result<Vec<u8>, CodecError>;
+    fn decode<D: DeserializeOwned>(&self, bytes: &[u8]) -> Result<D, CodecError>;
+}
+
+/// The default codec. Compact, but not self-describing.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BincodeCodec;
+
+impl BincodeCodec {
+    /// The same encoding as `bincode::serialize`, but limited to `limit`
+    /// bytes. A length prefix that claims more data than the frame holds
+    /// fails instead of making bincode allocate for it.
+    fn options(limit: usize) -> impl bincode::Options {
+        bincode::DefaultOptions::new()
+            .with_fixint_encoding()
+            .allow_trailing_bytes()
+            .with_limit(limit as u64)
+    }
+}
+
+impl Codec for BincodeCodec {
+    fn encode<S: Serialize>(&self, object: &S, limit: usize) -> Result<Vec<u8>, CodecError> {
+        Ok(Self::options(limit).serialize(object)?)
+    }
+
+    fn decode<D: DeserializeOwned>(&self, bytes: &[u8]) -> Result<D, CodecError> {
+        Ok(Self::options(bytes.len()).deserialize(bytes)?)
+    }
+}
+
+/// Self-describing, with struct fields encoded by name, which makes it
+/// easier to write clients in other languages.
+#[cfg(feature = "msgpack")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MessagePackCodec;
+
+#[cfg(feature = "msgpack")]
+impl Codec for MessagePackCodec {
+    // Oversized messages are caught by the frame size check when they're
+    // sent, so the limit isn't needed here.
+    fn encode<S: Serialize>(&self, object: &S, _limit: usize) -> Result<Vec<u8>, CodecError> {
+        Ok(rmp_serde::to_vec_named(object)?)
+    }
+
+    fn decode<D: DeserializeOwned>(&self, bytes: &[u8]) -> Result<D, CodecError> {
+        Ok(rmp_serde::from_slice(bytes)?)
+    }
+}
+
+/// A codec that a connection can use, chosen during the handshake.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Format {
+    #[default]
+    Bincode,
+    #[cfg(feature = "msgpack")]
+    MessagePack,
+}
+
+impl Format {
+    /// Every format this build supports.
+    pub const SUPPORTED: &'static [Format] = &[
+        Format::Bincode,
+        #[cfg(feature = "msgpack")]
+        Format::MessagePack,
+    ];
+
+    /// Identifies the format in the handshake. Stable across builds, whichever
+    /// features they have.
+    pub fn id(self) -> u8 {
+        match self {
+            Format::Bincode => 0,
+            #[cfg(feature = "msgpack")]
+            Format::MessagePack => 1,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        Self::SUPPORTED
+            .iter()
+            .copied()
+            .find(|format| format.id() == id)
+    }
+
+    /// The formats of this build as a bit set of their ids.
+    pub(crate) fn supported_mask() -> u8 {
+        Self::SUPPORTED
+            .iter()
+            .fold(0, |mask, format| mask | 1 << format.id())
+    }
+
+    /// Picks the format for a connection from what each side prefers and
+    /// supports. The result is the same on both ends: a format other than
+    /// bincode is used if one side prefers it and the other supports it, and
+    /// bincode otherwise.
+    pub(crate) fn negotiate(
+        my_preferred: Format,
+        peer_preferred: u8,
+        peer_supported: u8,
+    ) -> Format {
+        let peer_supports = |format: Format| peer_supported & (1 << format.id()) != 0;
+
+        let mine = Some(my_preferred).filter(|&format| peer_supports(format));
+        let theirs = Format::from_id(peer_preferred);
+
+        let mut candidates = mine
+            .into_iter()
+            .chain(theirs)
+            .filter(|&format| format != Format::Bincode);
+
+        match (candidates.next(), candidates.next()) {
+            (Some(first), None) => first,
+            (Some(first), Some(second)) if first == second => first,
+            _ => Format::Bincode,
+        }
+    }
+}
+
+impl Codec for Format {
+    fn encode<S: Serialize>(&self, object: &S, limit: usize) -> Result<Vec<u8>, CodecError> {
+        match self {
+            Format::Bincode => BincodeCodec.encode(object, limit),
+            #[cfg(feature = "msgpack")]
+            Format::MessagePack => MessagePackCodec.encode(object, limit),
+        }
+    }
+
+    fn decode<D: DeserializeOwned>(&self, bytes: &[u8]) -> Result<D, CodecError> {
+        match self {
+            Format::Bincode => BincodeCodec.decode(bytes),
+            #[cfg(feature = "msgpack")]
+            Format::MessagePack => MessagePackCodec.decode(bytes),
+        }
+    }
+}
@@ -1,5 +1,9 @@
+use crate::{
+    clock::{Clock, TokioClock},
+    codec::Format,
+};
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use std::{path::PathBuf, sync::Arc, time::Duration};
 
 const ONE_MEGABYTE: u64 = 1000000;
 
@@ -7,6 +11,94 @@ const DEFAULT_SMALL_FILE_THRESHOLD: u64 = ONE_MEGABYTE;
 const DEFAULT_BUNDLE_TARGET_SIZE: u64 = 64 * ONE_MEGABYTE;
 const DEFAULT_LARGE_FILE_THRESHOLD: u64 = 512 * ONE_MEGABYTE;
 
+const DEFAULT_DISCOVERY_CONCURRENCY: u32 = 16;
+const DEFAULT_TRANSFER_CONCURRENCY: u32 = 4;
+
+const DEFAULT_DISCOVERY_STALL_WARNING_MS: u64 = 30_000;
+
+const DEFAULT_MTIME_TOLERANCE_MS: u64 = 2000;
+
+const DEFAULT_LISTEN_BACKLOG: u32 = 1024;
+
+const DEFAULT_IDLE_TIMEOUT_MS: u64 = 600_000;
+
+const DEFAULT_RESUMPTION_TTL_MS: u64 = 60_000;
+const DEFAULT_MAX_SUSPENDED_SESSIONS: usize = 1024;
+
+const DEFAULT_HANDSHAKE_TIMEOUT_MS: u64 = 10_000;
+
+const DEFAULT_STALL_TIMEOUT_MS: u64 = 120_000;
+
+const DEFAULT_MAX_FRAME_SIZE: u32 = 16 * ONE_MEGABYTE as u32;
+// Leaves room for the frame header, the AEAD tag and a short message.
+const MIN_MAX_FRAME_SIZE: u32 = 256;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ConnectionConfig {
+    /// Mixed into key derivation so that deployments sharing a key don't
+    /// derive related session keys. Both peers must use the same context.
+    pub key_context: Option<String>,
+    /// Upper bound for a single frame on the wire, including the length prefix
+    /// and AEAD tag. Must be the same on both peers. Values below 256 are
+    /// raised to 256.
+    pub max_frame_size: Option<u32>,
+    /// How long each step of the handshake may take before the connection is
+    /// dropped.
+    pub handshake_timeout_ms: Option<u64>,
+    /// How long sending or receiving a frame may go without moving a single
+    /// byte before the connection is considered stalled. Waiting for the peer
+    /// to start a new frame doesn't count, so this doesn't limit idle time.
+    pub stall_timeout_ms: Option<u64>,
+    /// PKCS#8 encoded Ed25519 key identifying this peer. When set, the
+    /// handshake is signed with it, and the peer must be configured with one
+    /// too, or the handshake fails with `IdentityModeMismatch`. See
+    /// `crypto::generate_identity_key`.
+    ///
+    /// Note that this is an Ed25519 signing key, not an X25519 key: the
+    /// identity is proven by signing the ephemeral keys, which X25519 keys
+    /// can't do.
+    pub identity_key: Option<Vec<u8>>,
+    /// Public identity key the peer must prove it owns, as returned by
+    /// `crypto::identity_public_key`. Requires `identity_key`.
+    pub pinned_peer_key: Option<Vec<u8>>,
+    /// The message format to ask the peer for. Bincode is used unless both
+    /// peers support the other format and at least one of them prefers it.
+    pub format: Option<Format>,
+    /// Clock the handshake and stall timeouts are measured with. Servers
+    /// default to their own clock, everything else to the real one.
+    #[serde(skip)]
+    pub clock: Option<Arc<dyn Clock>>,
+}
+
+impl ConnectionConfig {
+    pub fn get_key_context(&self) -> &[u8] {
+        self.key_context
+            .as_ref()
+            .map(|context| context.as_bytes())
+            .unwrap_or_default()
+    }
+    pub fn get_format(&self) -> Format {
+        self.format.unwrap_or_default()
+    }
+    pub fn get_clock(&self) -> Arc<dyn Clock> {
+        self.clock.clone().unwrap_or_else(|| Arc::new(TokioClock))
+    }
+    pub fn get_stall_timeout(&self) -> Duration {
+        Duration::from_millis(self.stall_timeout_ms.unwrap_or(DEFAULT_STALL_TIMEOUT_MS))
+    }
+    pub fn get_max_frame_size(&self) -> usize {
+        self.max_frame_size
+            .unwrap_or(DEFAULT_MAX_FRAME_SIZE)
+            .max(MIN_MAX_FRAME_SIZE) as usize
+    }
+    pub fn get_handshake_timeout(&self) -> Duration {
+        Duration::from_millis(
+            self.handshake_timeout_ms
+                .unwrap_or(DEFAULT_HANDSHAKE_TIMEOUT_MS),
+        )
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ServerConfig {
     pub roots: Vec<PathBuf>,
@@ -14,6 +106,49 @@ pub struct ServerConfig {
     pub small_file_threshold_bytes: Option<u64>,
     pub large_file_threshold_bytes: Option<u64>,
     pub bundle_target_size: Option<u64>,
+    /// Modification times closer than this are considered equal when comparing
+    /// files, since not every filesystem stores sub-second (or even
+    /// single-second) precision.
+    pub mtime_tolerance_ms: Option<u64>,
+    /// Number of directories read in parallel during discovery. Discovery is
+    /// bound by metadata lookups, which benefit from many requests in flight,
+    /// especially on network filesystems.
+    pub discovery_concurrency: Option<u32>,
+    /// Number of bundles or files sent in parallel when a plan is run with
+    /// `TransferPlan::execute`. Transfers are bound by bandwidth, so a few
+    /// streams are usually enough to saturate the link.
+    ///
+    /// This only affects `TransferPlan::execute`. The server doesn't read it:
+    /// each session still sends its files one at a time, so parallel
+    /// transfers need one connection per stream.
+    pub transfer_concurrency: Option<u32>,
+    /// Warn if discovery has directories left but hasn't finished reading any
+    /// of them for this long, e.g. because a mount stopped responding.
+    pub discovery_stall_warning_ms: Option<u64>,
+    /// Hash every file's contents while discovering it. This reads every file
+    /// in full, which makes discovery much more IO intensive, but avoids a
+    /// second pass over the files when hashes are needed.
+    pub hash_during_discovery: Option<bool>,
+    /// Glob patterns of paths (relative to a root) that may be served. If
+    /// empty, everything not denied is served.
+    #[serde(default)]
+    pub allowed_paths: Vec<String>,
+    /// Glob patterns of paths that are never served, even if allowed.
+    #[serde(default)]
+    pub denied_paths: Vec<String>,
+    /// Maximum number of pending connections. The OS may silently cap this.
+    pub listen_backlog: Option<u32>,
+    /// Sessions that send nothing for this long are closed. Doesn't apply
+    /// while a file is being sent to the session.
+    pub idle_timeout_ms: Option<u64>,
+    /// How long the state of a session whose connection was lost is kept
+    /// around for the client to resume it.
+    pub resumption_ttl_ms: Option<u64>,
+    /// How many sessions waiting to be resumed are kept at most. Once there
+    /// are this many, the one closest to expiring is dropped to make room.
+    pub max_suspended_sessions: Option<usize>,
+    #[serde(default)]
+    pub connection: ConnectionConfig,
 }
 
 impl Default for ServerConfig {
@@ -23,6 +158,18 @@ impl Default for ServerConfig {
             small_file_threshold_bytes: Some(DEFAULT_SMALL_FILE_THRESHOLD),
             large_file_threshold_bytes: Some(DEFAULT_LARGE_FILE_THRESHOLD),
             bundle_target_size: Some(DEFAULT_BUNDLE_TARGET_SIZE),
+            mtime_tolerance_ms: Some(DEFAULT_MTIME_TOLERANCE_MS),
+            discovery_concurrency: Some(DEFAULT_DISCOVERY_CONCURRENCY),
+            transfer_concurrency: Some(DEFAULT_TRANSFER_CONCURRENCY),
+            discovery_stall_warning_ms: Some(DEFAULT_DISCOVERY_STALL_WARNING_MS),
+            hash_during_discovery: Some(false),
+            allowed_paths: Vec::new(),
+            denied_paths: Vec::new(),
+            listen_backlog: Some(DEFAULT_LISTEN_BACKLOG),
+            idle_timeout_ms: Some(DEFAULT_IDLE_TIMEOUT_MS),
+            resumption_ttl_ms: Some(DEFAULT_RESUMPTION_TTL_MS),
+            max_suspended_sessions: Some(DEFAULT_MAX_SUSPENDED_SESSIONS),
+            connection: ConnectionConfig::default(),
         }
     }
 }
@@ -36,4 +183,47 @@ impl ServerConfig {
         self.large_file_threshold_bytes
             .unwrap_or(DEFAULT_LARGE_FILE_THRESHOLD)
     }
+    pub fn get_bundle_target_size(&self) -> u64 {
+        self.bundle_target_size
+            .unwrap_or(DEFAULT_BUNDLE_TARGET_SIZE)
+    }
+    pub fn get_mtime_tolerance(&self) -> Duration {
+        Duration::from_millis(
+            self.mtime_tolerance_ms
+                .unwrap_or(DEFAULT_MTIME_TOLERANCE_MS),
+        )
+    }
+    // A concurrency of zero would never make progress, so it's raised to one.
+    pub fn get_discovery_concurrency(&self) -> u32 {
+        self.discovery_concurrency
+            .unwrap_or(DEFAULT_DISCOVERY_CONCURRENCY)
+            .max(1)
+    }
+    pub fn get_transfer_concurrency(&self) -> u32 {
+        self.transfer_concurrency
+            .unwrap_or(DEFAULT_TRANSFER_CONCURRENCY)
+            .max(1)
+    }
+    pub fn get_discovery_stall_warning(&self) -> Duration {
+        Duration::from_millis(
+            self.discovery_stall_warning_ms
+                .unwrap_or(DEFAULT_DISCOVERY_STALL_WARNING_MS),
+        )
+    }
+    pub fn get_hash_during_discovery(&self) -> bool {
+        self.hash_during_discovery.unwrap_or(false)
+    }
+    pub fn get_listen_backlog(&self) -> u32 {
+        self.listen_backlog.unwrap_or(DEFAULT_LISTEN_BACKLOG)
+    }
+    pub fn get_idle_timeout(&self) -> Duration {
+        Duration::from_millis(self.idle_timeout_ms.unwrap_or(DEFAULT_IDLE_TIMEOUT_MS))
+    }
+    pub fn get_resumption_ttl(&self) -> Duration {
+        Duration::from_millis(self.resumption_ttl_ms.unwrap_or(DEFAULT_RESUMPTION_TTL_MS))
+    }
+    pub fn get_max_suspended_sessions(&self) -> usize {
+        self.max_suspended_sessions
+            .unwrap_or(DEFAULT_MAX_SUSPENDED_SESSIONS)
+    }
 }
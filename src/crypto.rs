@@ -1,15 +1,110 @@
+use crate::{
+    clock::{timeout, Clock},
+    codec::{Codec, CodecError, Format},
+    config::ConnectionConfig,
+    protocol::{ResumptionToken, SESSION_ID_LEN},
+};
+use async_trait::async_trait;
 use ring::{
     aead::{Aad, BoundKey, NonceSequence, OpeningKey, SealingKey, UnboundKey},
     agreement::{EphemeralPrivateKey, UnparsedPublicKey},
     hkdf::{Prk, Salt},
+    signature::{Ed25519KeyPair, KeyPair},
 };
 use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use thiserror::Error;
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::TcpStream,
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpStream,
+    },
 };
 
-const KEY_INFO: &'static [u8] = b"pneumatic-key";
+const KEY_INFO: &[u8] = b"pneumatic-key";
+
+/// Prefix of the transcript signed by each peer's identity key, so that the
+/// signature can't be mistaken for one made for some other purpose.
+const IDENTITY_SIGNATURE_PREFIX: &[u8] = b"pneumatic-identity";
+
+/// Handshake flag set by peers that are configured with an identity key.
+const FLAG_IDENTITY: u8 = 0b1;
+
+/// Handshake flag set by a peer that resumes a session instead of agreeing on
+/// new keys.
+const FLAG_RESUME: u8 = 0b10;
+
+const ED25519_PUBLIC_KEY_LEN: usize = 32;
+const ED25519_SIGNATURE_LEN: usize = 64;
+
+/// Version of the frame layout. Bumped whenever the framing changes so that
+/// peers can reject frames they don't understand instead of misparsing them.
+pub const FRAME_FORMAT_VERSION: u8 = 1;
+
+/// Every frame starts with the length of its ciphertext as a big endian u32,
+/// followed by the frame format version.
+pub const FRAME_HEADER_LEN: usize = std::mem::size_of::<u32>() + std::mem::size_of::<u8>();
+
+// A receive buffer is shrunk once a frame uses less than a quarter of its
+// capacity, but never below MIN_RETAINED_BUFFER_CAPACITY. The gap between the
+// two keeps frames of similar sizes from repeatedly reallocating the buffer.
+const BUFFER_SHRINK_FACTOR: usize = 4;
+const MIN_RETAINED_BUFFER_CAPACITY: usize = 64 * 1024;
+
+#[derive(Debug, Error)]
+pub enum HandshakeError {
+    #[error("handshake timed out")]
+    Timeout,
+    #[error("peer closed the connection during the handshake")]
+    ShortRead,
+    #[error("key agreement failed")]
+    KeyAgreement,
+    #[error("only one of the peers is configured with an identity key")]
+    IdentityModeMismatch,
+    #[error("a pinned peer key requires an identity key")]
+    MissingIdentity,
+    #[error("identity key is not a valid PKCS#8 Ed25519 key")]
+    InvalidIdentity,
+    #[error("peer's identity signature is invalid")]
+    InvalidSignature,
+    #[error("peer's identity key doesn't match the pinned key")]
+    PeerKeyMismatch,
+    #[error("session to resume is unknown or has expired")]
+    ResumptionRejected,
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+#[derive(Debug, Error)]
+pub enum StreamError {
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("frame of {size} bytes exceeds the maximum of {max} bytes")]
+    FrameTooLarge { size: usize, max: usize },
+    #[error("failed to decrypt frame")]
+    Decrypt,
+    #[error("unsupported frame format version {0}")]
+    UnsupportedFrameVersion(u8),
+    #[error("peer made no progress within the stall timeout")]
+    StallTimeout,
+    #[error("serialization error: {0}")]
+    Serialization(#[from] CodecError),
+}
+
+impl StreamError {
+    /// Whether the connection itself failed, as opposed to the peer sending
+    /// something it shouldn't have.
+    pub fn is_connection_lost(&self) -> bool {
+        matches!(self, StreamError::Io(_) | StreamError::StallTimeout)
+    }
+}
 
 struct Salts {
     encrypt_salt: Salt,
@@ -18,6 +113,7 @@ struct Salts {
 
 struct InitialKeys {
     my_private_key: EphemeralPrivateKey,
+    my_public_key: Vec<u8>,
     peer_public_key: UnparsedPublicKey<Vec<u8>>,
 }
 
@@ -34,6 +130,12 @@ impl NonceCounter {
     }
 }
 
+impl Default for NonceCounter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl NonceSequence for NonceCounter {
     fn advance(&mut self) -> Result<ring::aead::Nonce, ring::error::Unspecified> {
         self.0 += 1;
@@ -46,7 +148,23 @@ impl NonceSequence for NonceCounter {
     }
 }
 
-async fn exchange_keys(stream: &mut TcpStream, rng: &impl ring::rand::SecureRandom) -> InitialKeys {
+async fn read_handshake_bytes(
+    stream: &mut TcpStream,
+    buffer: &mut [u8],
+) -> Result<(), HandshakeError> {
+    match stream.read_exact(buffer).await {
+        Ok(_) => Ok(()),
+        Err(error) if error.kind() == std::io::ErrorKind::UnexpectedEof => {
+            Err(HandshakeError::ShortRead)
+        }
+        Err(error) => Err(error.into()),
+    }
+}
+
+async fn exchange_keys(
+    stream: &mut TcpStream,
+    rng: &impl ring::rand::SecureRandom,
+) -> Result<InitialKeys, HandshakeError> {
     let my_private_key =
         ring::agreement::EphemeralPrivateKey::generate(&ring::agreement::X25519, rng).unwrap();
     let my_public_key = my_private_key.compute_public_key().unwrap();
@@ -55,41 +173,222 @@ async fn exchange_keys(stream: &mut TcpStream, rng: &impl ring::rand::SecureRand
 
     // Send public key
     let my_public_key_bytes: &[u8] = my_public_key.as_ref();
-    stream.write_all(my_public_key_bytes).await.unwrap();
+    stream.write_all(my_public_key_bytes).await?;
 
     // Read peer public key
     let mut peer_public_key_bytes = vec![0u8; 32];
-    stream.read_exact(&mut peer_public_key_bytes).await.unwrap();
+    read_handshake_bytes(stream, &mut peer_public_key_bytes).await?;
 
     let peer_public_key =
         ring::agreement::UnparsedPublicKey::new(&ring::agreement::X25519, peer_public_key_bytes);
 
-    InitialKeys {
+    Ok(InitialKeys {
         my_private_key,
+        my_public_key: my_public_key_bytes.to_vec(),
         peer_public_key,
+    })
+}
+
+/// Generates a new PKCS#8 encoded Ed25519 identity key.
+pub fn generate_identity_key() -> Vec<u8> {
+    let rng = ring::rand::SystemRandom::new();
+    Ed25519KeyPair::generate_pkcs8(&rng)
+        .unwrap()
+        .as_ref()
+        .to_vec()
+}
+
+/// Returns the public half of a PKCS#8 encoded identity key, which is what the
+/// peer pins.
+pub fn identity_public_key(identity_key: &[u8]) -> Result<Vec<u8>, HandshakeError> {
+    let key_pair =
+        Ed25519KeyPair::from_pkcs8(identity_key).map_err(|_| HandshakeError::InvalidIdentity)?;
+    Ok(key_pair.public_key().as_ref().to_vec())
+}
+
+// The signed transcript is the signer's ephemeral key followed by the
+// verifier's, so a signature can't be replayed in another session or reflected
+// back at its sender.
+fn identity_transcript(signer_key: &[u8], verifier_key: &[u8]) -> Vec<u8> {
+    [IDENTITY_SIGNATURE_PREFIX, signer_key, verifier_key].concat()
+}
+
+/// Proves ownership of our identity key by signing both ephemeral keys, and
+/// checks that the peer does the same with the pinned key. This binds the key
+/// agreement to the long-term keys, so a man in the middle can't substitute
+/// its own ephemeral key.
+async fn exchange_identities(
+    stream: &mut TcpStream,
+    identity_key: &[u8],
+    pinned_peer_key: Option<&[u8]>,
+    keys: &InitialKeys,
+) -> Result<(), HandshakeError> {
+    let key_pair =
+        Ed25519KeyPair::from_pkcs8(identity_key).map_err(|_| HandshakeError::InvalidIdentity)?;
+    let peer_ephemeral_key = keys.peer_public_key.bytes();
+
+    let signature = key_pair.sign(&identity_transcript(
+        &keys.my_public_key,
+        peer_ephemeral_key,
+    ));
+    stream.write_all(key_pair.public_key().as_ref()).await?;
+    stream.write_all(signature.as_ref()).await?;
+
+    let mut peer_identity = vec![0u8; ED25519_PUBLIC_KEY_LEN];
+    read_handshake_bytes(stream, &mut peer_identity).await?;
+    let mut peer_signature = vec![0u8; ED25519_SIGNATURE_LEN];
+    read_handshake_bytes(stream, &mut peer_signature).await?;
+
+    let peer_key =
+        ring::signature::UnparsedPublicKey::new(&ring::signature::ED25519, &peer_identity);
+    peer_key
+        .verify(
+            &identity_transcript(peer_ephemeral_key, &keys.my_public_key),
+            &peer_signature,
+        )
+        .map_err(|_| HandshakeError::InvalidSignature)?;
+
+    match pinned_peer_key {
+        Some(pinned) if pinned != &peer_identity[..] => Err(HandshakeError::PeerKeyMismatch),
+        _ => Ok(()),
+    }
+}
+
+/// Tells the peer which optional handshake steps we are going to perform, and
+/// checks that it is going to perform the same ones. Without this, a peer
+/// without an identity key would misread our identity as its salt.
+///
+/// Returns the peer's flags.
+async fn exchange_flags(stream: &mut TcpStream, flags: u8) -> Result<u8, HandshakeError> {
+    stream.write_all(&[flags]).await?;
+
+    let mut peer_flags = [0u8; 1];
+    read_handshake_bytes(stream, &mut peer_flags).await?;
+    let [peer_flags] = peer_flags;
+
+    if (peer_flags & FLAG_IDENTITY) != (flags & FLAG_IDENTITY) {
+        return Err(HandshakeError::IdentityModeMismatch);
+    }
+
+    // Only one side can be the one that resumes.
+    if (peer_flags & FLAG_RESUME) != 0 && (flags & FLAG_RESUME) != 0 {
+        return Err(HandshakeError::ResumptionRejected);
+    }
+
+    Ok(peer_flags)
+}
+
+/// Looks up the secret of a session that a peer wants to resume, for the
+/// side of the handshake that accepts resumptions.
+#[async_trait]
+pub trait ResumptionSecrets: Send + Sync {
+    async fn resumption_secret(&self, session_id: &[u8]) -> Option<Vec<u8>>;
+}
+
+/// How a handshake can skip the key exchange.
+enum Resumption<'a> {
+    /// Resume the session `token` was handed out for.
+    Offer(&'a ResumptionToken),
+    /// Let the peer resume any session `secrets` knows of.
+    Accept(&'a dyn ResumptionSecrets),
+    None,
+}
+
+/// What the session keys are derived from.
+enum KeyMaterial {
+    Agreement(InitialKeys),
+    /// The secret of a resumed session.
+    Resumed(Vec<u8>),
+}
+
+/// Names the session to resume, and waits for the peer to say whether it
+/// knows it. Returns the secret to derive keys from.
+async fn offer_resumption<'a>(
+    stream: &mut TcpStream,
+    token: &'a ResumptionToken,
+) -> Result<&'a [u8], HandshakeError> {
+    let (session_id, secret) = token.split().ok_or(HandshakeError::ResumptionRejected)?;
+    stream.write_all(session_id).await?;
+
+    let mut accepted = [0u8; 1];
+    read_handshake_bytes(stream, &mut accepted).await?;
+
+    match accepted {
+        [1] => Ok(secret),
+        _ => Err(HandshakeError::ResumptionRejected),
+    }
+}
+
+/// Reads the session the peer wants to resume and tells it whether we know
+/// it. Returns the secret to derive keys from.
+async fn accept_resumption(
+    stream: &mut TcpStream,
+    secrets: Option<&dyn ResumptionSecrets>,
+) -> Result<Vec<u8>, HandshakeError> {
+    let mut session_id = [0u8; SESSION_ID_LEN];
+    read_handshake_bytes(stream, &mut session_id).await?;
+
+    let secret = match secrets {
+        Some(secrets) => secrets.resumption_secret(&session_id).await,
+        None => None,
+    };
+
+    match secret {
+        Some(secret) => {
+            stream.write_all(&[1]).await?;
+            Ok(secret)
+        }
+        None => {
+            stream.write_all(&[0]).await?;
+            Err(HandshakeError::ResumptionRejected)
+        }
     }
 }
 
-async fn exchange_salt(stream: &mut TcpStream, rng: &impl ring::rand::SecureRandom) -> Salts {
+async fn exchange_salt(
+    stream: &mut TcpStream,
+    rng: &impl ring::rand::SecureRandom,
+) -> Result<Salts, HandshakeError> {
     let mut my_salt = vec![0u8; 32];
     rng.fill(&mut my_salt).unwrap();
-    stream.write_all(&my_salt).await.unwrap();
+    stream.write_all(&my_salt).await?;
 
     let mut other_salt = vec![0u8; 32];
-    stream.read_exact(&mut other_salt).await.unwrap();
+    read_handshake_bytes(stream, &mut other_salt).await?;
 
     let encrypt_salt = Salt::new(ring::hkdf::HKDF_SHA256, &my_salt);
     let decrypt_salt = Salt::new(ring::hkdf::HKDF_SHA256, &other_salt);
 
-    Salts {
+    Ok(Salts {
         encrypt_salt,
         decrypt_salt,
-    }
+    })
+}
+
+/// Tells the peer which message format we prefer and which ones we support,
+/// and picks one the same way it does.
+async fn exchange_formats(
+    stream: &mut TcpStream,
+    preferred: Format,
+) -> Result<Format, HandshakeError> {
+    stream
+        .write_all(&[preferred.id(), Format::supported_mask()])
+        .await?;
+
+    let mut peer_formats = [0u8; 2];
+    read_handshake_bytes(stream, &mut peer_formats).await?;
+    let [peer_preferred, peer_supported] = peer_formats;
+
+    Ok(Format::negotiate(preferred, peer_preferred, peer_supported))
 }
 
-fn expand_key(prk: Prk) -> [u8; 32] {
+/// Expands a session key from `prk`. The HKDF info is `KEY_INFO` followed by
+/// `context`, so an empty context derives the same key as before contexts
+/// were introduced.
+pub fn expand_key(prk: Prk, context: &[u8]) -> [u8; 32] {
     let mut key = [0u8; 32];
-    let okm = prk.expand(&[KEY_INFO], ring::hkdf::HKDF_SHA256).unwrap();
+    let info = [KEY_INFO, context];
+    let okm = prk.expand(&info, ring::hkdf::HKDF_SHA256).unwrap();
     okm.fill(&mut key).unwrap();
     key
 }
@@ -100,10 +399,15 @@ fn bind_key<B: BoundKey<NonceCounter>>(key: [u8; 32]) -> B {
     B::new(unbound_key, nonce_sequence)
 }
 
-fn derive_keys(initial_keys: InitialKeys, salts: Salts) -> Keys {
+fn derive_keys(
+    initial_keys: InitialKeys,
+    salts: Salts,
+    context: &[u8],
+) -> Result<Keys, HandshakeError> {
     let InitialKeys {
         my_private_key,
         peer_public_key,
+        ..
     } = initial_keys;
 
     let (encrypt_prk, decrypt_prk) = ring::agreement::agree_ephemeral(
@@ -112,15 +416,28 @@ fn derive_keys(initial_keys: InitialKeys, salts: Salts) -> Keys {
         ring::error::Unspecified,
         |secret| {
             Ok((
-                salts.encrypt_salt.extract(&secret),
-                salts.decrypt_salt.extract(&secret),
+                salts.encrypt_salt.extract(secret),
+                salts.decrypt_salt.extract(secret),
             ))
         },
     )
-    .unwrap();
+    .map_err(|_| HandshakeError::KeyAgreement)?;
+
+    Ok(expand_keys(encrypt_prk, decrypt_prk, context))
+}
+
+/// Like `derive_keys`, but from the secret of a resumed session instead of a
+/// key agreement. Fresh salts keep the keys different on every connection.
+fn derive_resumed_keys(secret: &[u8], salts: Salts, context: &[u8]) -> Keys {
+    let encrypt_prk = salts.encrypt_salt.extract(secret);
+    let decrypt_prk = salts.decrypt_salt.extract(secret);
+
+    expand_keys(encrypt_prk, decrypt_prk, context)
+}
 
-    let encrypt_key = expand_key(encrypt_prk);
-    let decrypt_key = expand_key(decrypt_prk);
+fn expand_keys(encrypt_prk: Prk, decrypt_prk: Prk, context: &[u8]) -> Keys {
+    let encrypt_key = expand_key(encrypt_prk, context);
+    let decrypt_key = expand_key(decrypt_prk, context);
 
     Keys {
         encrypt_key: bind_key(encrypt_key),
@@ -128,51 +445,442 @@ fn derive_keys(initial_keys: InitialKeys, salts: Salts) -> Keys {
     }
 }
 
+/// Traffic of one or more streams, counted in frames and in bytes on the wire.
+/// The handshake isn't included.
+#[derive(Debug, Default)]
+pub struct TrafficCounters {
+    pub frames_sent: AtomicU64,
+    pub frames_received: AtomicU64,
+    pub bytes_sent: AtomicU64,
+    pub bytes_received: AtomicU64,
+}
+
+impl TrafficCounters {
+    fn frame_sent(&self, bytes: usize) {
+        self.frames_sent.fetch_add(1, Ordering::Relaxed);
+        self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    fn frame_received(&self, bytes: usize) {
+        self.frames_received.fetch_add(1, Ordering::Relaxed);
+        self.bytes_received
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+}
+
+pub struct EncryptedReadHalf {
+    stream: OwnedReadHalf,
+    decrypt_key: OpeningKey<NonceCounter>,
+    format: Format,
+    max_frame_size: usize,
+    stall_timeout: Duration,
+    clock: Arc<dyn Clock>,
+    counters: Option<Arc<TrafficCounters>>,
+}
+
+/// Like `read_exact`, but fails with `StallTimeout` if no bytes arrive for
+/// `stall_timeout`. A slow peer is fine as long as it keeps sending something.
+async fn read_exact_or_stall<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    buffer: &mut [u8],
+    stall_timeout: Duration,
+    clock: &dyn Clock,
+) -> Result<(), StreamError> {
+    let mut filled = 0;
+
+    while filled < buffer.len() {
+        let read = timeout(clock, stall_timeout, reader.read(&mut buffer[filled..]))
+            .await
+            .map_err(|_| StreamError::StallTimeout)??;
+
+        if read == 0 {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        }
+
+        filled += read;
+    }
+
+    Ok(())
+}
+
+/// Like `write_all`, but fails with `StallTimeout` if the peer doesn't accept
+/// any bytes for `stall_timeout`.
+async fn write_all_or_stall<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    buffer: &[u8],
+    stall_timeout: Duration,
+    clock: &dyn Clock,
+) -> Result<(), StreamError> {
+    let mut written = 0;
+
+    while written < buffer.len() {
+        let wrote = timeout(clock, stall_timeout, writer.write(&buffer[written..]))
+            .await
+            .map_err(|_| StreamError::StallTimeout)??;
+
+        if wrote == 0 {
+            return Err(std::io::Error::from(std::io::ErrorKind::WriteZero).into());
+        }
+
+        written += wrote;
+    }
+
+    Ok(())
+}
+
+impl EncryptedReadHalf {
+    pub async fn receive_buffer<'a>(
+        &mut self,
+        buffer: &'a mut Vec<u8>,
+    ) -> Result<&'a [u8], StreamError> {
+        let mut header = [0u8; FRAME_HEADER_LEN];
+
+        // An idle peer may take as long as it wants to start the next frame,
+        // but once it has, the rest of the frame must keep arriving.
+        self.stream.read_exact(&mut header[..1]).await?;
+        read_exact_or_stall(
+            &mut self.stream,
+            &mut header[1..],
+            self.stall_timeout,
+            &*self.clock,
+        )
+        .await?;
+
+        let mut length = [0u8; 4];
+        length.copy_from_slice(&header[..4]);
+        let buffer_length = u32::from_be_bytes(length) as usize;
+        let version = header[4];
+
+        if version != FRAME_FORMAT_VERSION {
+            return Err(StreamError::UnsupportedFrameVersion(version));
+        }
+
+        let max_length = self.max_frame_size - FRAME_HEADER_LEN;
+
+        if buffer_length > max_length {
+            return Err(StreamError::FrameTooLarge {
+                size: buffer_length,
+                max: max_length,
+            });
+        }
+
+        buffer.resize_with(buffer_length, Default::default);
+
+        if buffer.capacity() > MIN_RETAINED_BUFFER_CAPACITY
+            && buffer_length < buffer.capacity() / BUFFER_SHRINK_FACTOR
+        {
+            buffer.shrink_to(buffer_length.max(MIN_RETAINED_BUFFER_CAPACITY));
+        }
+
+        read_exact_or_stall(&mut self.stream, buffer, self.stall_timeout, &*self.clock).await?;
+
+        if let Some(counters) = &self.counters {
+            counters.frame_received(FRAME_HEADER_LEN + buffer_length);
+        }
+
+        self.decrypt_key
+            .open_in_place(Aad::from([version]), buffer)
+            .map(|decrypted| &*decrypted)
+            .map_err(|_| StreamError::Decrypt)
+    }
+
+    /// Receives a message in the format negotiated during the handshake.
+    pub async fn receive<D: DeserializeOwned>(
+        &mut self,
+        buffer: &mut Vec<u8>,
+    ) -> Result<D, StreamError> {
+        let format = self.format;
+        let decrypted = self.receive_buffer(buffer).await?;
+        Ok(format.decode(decrypted)?)
+    }
+}
+
+/// The sending half of an encrypted stream. Frames are buffered, so callers
+/// must call `flush` after sending a burst of frames.
+pub struct EncryptedWriteHalf {
+    stream: BufWriter<OwnedWriteHalf>,
+    encrypt_key: SealingKey<NonceCounter>,
+    format: Format,
+    max_frame_size: usize,
+    stall_timeout: Duration,
+    clock: Arc<dyn Clock>,
+    counters: Option<Arc<TrafficCounters>>,
+}
+
+impl EncryptedWriteHalf {
+    /// Bytes added to every frame on top of its plaintext: the AEAD tag of the
+    /// negotiated cipher and the frame header.
+    pub fn frame_overhead(&self) -> usize {
+        self.encrypt_key.algorithm().tag_len() + FRAME_HEADER_LEN
+    }
+
+    /// The largest plaintext that fits in a single frame of `max_frame_size`.
+    pub fn max_plaintext(&self) -> usize {
+        self.max_frame_size - self.frame_overhead()
+    }
+
+    pub async fn send_buffer(&mut self, buffer: &mut Vec<u8>) -> Result<(), StreamError> {
+        let max_plaintext = self.max_plaintext();
+
+        if buffer.len() > max_plaintext {
+            return Err(StreamError::FrameTooLarge {
+                size: buffer.len(),
+                max: max_plaintext,
+            });
+        }
+
+        // The version is authenticated as associated data, so it can't be
+        // altered without the frame failing to decrypt.
+        self.encrypt_key
+            .seal_in_place_append_tag(Aad::from([FRAME_FORMAT_VERSION]), buffer)
+            .unwrap();
+
+        let mut header = [0u8; FRAME_HEADER_LEN];
+        header[..4].copy_from_slice(&(buffer.len() as u32).to_be_bytes());
+        header[4] = FRAME_FORMAT_VERSION;
+
+        write_all_or_stall(&mut self.stream, &header, self.stall_timeout, &*self.clock).await?;
+        write_all_or_stall(&mut self.stream, buffer, self.stall_timeout, &*self.clock).await?;
+
+        if let Some(counters) = &self.counters {
+            counters.frame_sent(FRAME_HEADER_LEN + buffer.len());
+        }
+
+        Ok(())
+    }
+
+    /// Sends a message in the format negotiated during the handshake.
+    pub async fn send<S: Serialize>(&mut self, object: &S) -> Result<(), StreamError> {
+        let mut buffer = self.format.encode(object, self.max_plaintext())?;
+        self.send_buffer(&mut buffer).await
+    }
+
+    /// Writes out every buffered frame.
+    pub async fn flush(&mut self) -> Result<(), StreamError> {
+        // The write buffer is small, so flushing it all is one unit of progress.
+        timeout(&*self.clock, self.stall_timeout, self.stream.flush())
+            .await
+            .map_err(|_| StreamError::StallTimeout)??;
+        Ok(())
+    }
+}
+
 pub struct EncryptedStream {
-    stream: TcpStream,
-    keys: Keys,
+    reader: EncryptedReadHalf,
+    writer: EncryptedWriteHalf,
 }
 
 impl EncryptedStream {
-    pub async fn send_buffer(&mut self, buffer: &mut Vec<u8>) {
-        self.keys
-            .encrypt_key
-            .seal_in_place_append_tag(Aad::empty(), buffer)
-            .unwrap();
+    pub fn frame_overhead(&self) -> usize {
+        self.writer.frame_overhead()
+    }
 
-        self.stream.write_u32(buffer.len() as u32).await.unwrap();
-        self.stream.write_all(buffer).await.unwrap();
+    pub fn max_plaintext(&self) -> usize {
+        self.writer.max_plaintext()
     }
 
-    pub async fn receive_buffer<'a>(&mut self, buffer: &'a mut Vec<u8>) -> &'a [u8] {
-        let buffer_length = self.stream.read_u32().await.unwrap();
-        buffer.resize_with(buffer_length as usize, Default::default);
+    pub async fn send_buffer(&mut self, buffer: &mut Vec<u8>) -> Result<(), StreamError> {
+        self.writer.send_buffer(buffer).await
+    }
 
-        self.stream.read_exact(buffer).await.unwrap();
+    /// Writes out every buffered frame. Receiving flushes automatically, so
+    /// this is only needed when sending without waiting for a reply.
+    pub async fn flush(&mut self) -> Result<(), StreamError> {
+        self.writer.flush().await
+    }
 
-        self.keys
-            .decrypt_key
-            .open_in_place(Aad::empty(), buffer)
-            .unwrap()
+    pub async fn receive_buffer<'a>(
+        &mut self,
+        buffer: &'a mut Vec<u8>,
+    ) -> Result<&'a [u8], StreamError> {
+        // The peer may be waiting for something we've buffered before it
+        // sends anything back.
+        self.writer.flush().await?;
+        self.reader.receive_buffer(buffer).await
     }
 
-    pub async fn send_bincode<S: Serialize>(&mut self, object: &S) {
-        let mut buffer = bincode::serialize(object).unwrap();
-        self.send_buffer(&mut buffer).await;
+    /// The message format negotiated during the handshake.
+    pub fn format(&self) -> Format {
+        self.writer.format
     }
 
-    pub async fn receive_bincode<D: DeserializeOwned>(&mut self, buffer: &mut Vec<u8>) -> D {
-        let decrypted = self.receive_buffer(buffer).await;
-        bincode::deserialize(&decrypted).unwrap()
+    /// Counts every frame sent or received from now on in `counters`, which
+    /// may be shared with other streams. Carries over to the halves if the
+    /// stream is split.
+    pub fn count_traffic(&mut self, counters: Arc<TrafficCounters>) {
+        self.reader.counters = Some(counters.clone());
+        self.writer.counters = Some(counters);
     }
 
-    pub async fn new(mut stream: TcpStream) -> Self {
-        let rng = ring::rand::SystemRandom::new();
+    pub async fn send<S: Serialize>(&mut self, object: &S) -> Result<(), StreamError> {
+        self.writer.send(object).await
+    }
+
+    pub async fn receive<D: DeserializeOwned>(
+        &mut self,
+        buffer: &mut Vec<u8>,
+    ) -> Result<D, StreamError> {
+        self.writer.flush().await?;
+        self.reader.receive(buffer).await
+    }
 
-        let keys = exchange_keys(&mut stream, &rng).await;
-        let salts = exchange_salt(&mut stream, &rng).await;
-        let keys = derive_keys(keys, salts);
+    /// Splits the stream so that one task can receive while another sends.
+    /// The halves are independent, so the write half must be flushed
+    /// explicitly.
+    pub fn split(self) -> (EncryptedReadHalf, EncryptedWriteHalf) {
+        (self.reader, self.writer)
+    }
+
+    /// Performs the handshake over `stream`. Each step of the handshake must
+    /// complete within the configured handshake timeout, so a peer that stalls
+    /// can't keep the connection open indefinitely.
+    pub async fn new(stream: TcpStream, config: &ConnectionConfig) -> Result<Self, HandshakeError> {
+        Self::handshake(stream, config, Resumption::None).await
+    }
 
-        EncryptedStream { stream, keys }
+    /// Like `new`, but derives the keys from `token` instead of agreeing on
+    /// new ones. Fails with `ResumptionRejected` if the peer doesn't have the
+    /// session any more.
+    ///
+    /// The identities aren't checked again, since they were checked when the
+    /// session was started and only the two peers know the token. Anyone who
+    /// learns it can read the resumed connection, though, and it doesn't
+    /// have the forward secrecy of a fresh key exchange.
+    pub async fn resume(
+        stream: TcpStream,
+        config: &ConnectionConfig,
+        token: &ResumptionToken,
+    ) -> Result<Self, HandshakeError> {
+        Self::handshake(stream, config, Resumption::Offer(token)).await
+    }
+
+    /// Like `new`, but lets the peer resume any session whose secret is in
+    /// `secrets`.
+    pub async fn accept(
+        stream: TcpStream,
+        config: &ConnectionConfig,
+        secrets: &dyn ResumptionSecrets,
+    ) -> Result<Self, HandshakeError> {
+        Self::handshake(stream, config, Resumption::Accept(secrets)).await
+    }
+
+    async fn handshake(
+        mut stream: TcpStream,
+        config: &ConnectionConfig,
+        resumption: Resumption<'_>,
+    ) -> Result<Self, HandshakeError> {
+        if config.pinned_peer_key.is_some() && config.identity_key.is_none() {
+            return Err(HandshakeError::MissingIdentity);
+        }
+
+        let rng = ring::rand::SystemRandom::new();
+        let handshake_timeout = config.get_handshake_timeout();
+        let clock = config.get_clock();
+
+        let mut flags = if config.identity_key.is_some() {
+            FLAG_IDENTITY
+        } else {
+            0
+        };
+        if let Resumption::Offer(_) = resumption {
+            flags |= FLAG_RESUME;
+        }
+        let peer_flags = timeout(
+            &*clock,
+            handshake_timeout,
+            exchange_flags(&mut stream, flags),
+        )
+        .await
+        .map_err(|_| HandshakeError::Timeout)??;
+
+        let material = match resumption {
+            Resumption::Offer(token) => {
+                let secret = timeout(
+                    &*clock,
+                    handshake_timeout,
+                    offer_resumption(&mut stream, token),
+                )
+                .await
+                .map_err(|_| HandshakeError::Timeout)??;
+                KeyMaterial::Resumed(secret.to_vec())
+            }
+            _ if (peer_flags & FLAG_RESUME) != 0 => {
+                let secrets = match resumption {
+                    Resumption::Accept(secrets) => Some(secrets),
+                    _ => None,
+                };
+                let secret = timeout(
+                    &*clock,
+                    handshake_timeout,
+                    accept_resumption(&mut stream, secrets),
+                )
+                .await
+                .map_err(|_| HandshakeError::Timeout)??;
+                KeyMaterial::Resumed(secret)
+            }
+            _ => {
+                let keys = timeout(&*clock, handshake_timeout, exchange_keys(&mut stream, &rng))
+                    .await
+                    .map_err(|_| HandshakeError::Timeout)??;
+
+                if let Some(identity_key) = &config.identity_key {
+                    let pinned_peer_key = config.pinned_peer_key.as_deref();
+                    timeout(
+                        &*clock,
+                        handshake_timeout,
+                        exchange_identities(&mut stream, identity_key, pinned_peer_key, &keys),
+                    )
+                    .await
+                    .map_err(|_| HandshakeError::Timeout)??;
+                }
+
+                KeyMaterial::Agreement(keys)
+            }
+        };
+
+        let salts = timeout(&*clock, handshake_timeout, exchange_salt(&mut stream, &rng))
+            .await
+            .map_err(|_| HandshakeError::Timeout)??;
+        let format = timeout(
+            &*clock,
+            handshake_timeout,
+            exchange_formats(&mut stream, config.get_format()),
+        )
+        .await
+        .map_err(|_| HandshakeError::Timeout)??;
+        let Keys {
+            encrypt_key,
+            decrypt_key,
+        } = match material {
+            KeyMaterial::Agreement(keys) => derive_keys(keys, salts, config.get_key_context())?,
+            KeyMaterial::Resumed(secret) => {
+                derive_resumed_keys(&secret, salts, config.get_key_context())
+            }
+        };
+
+        let max_frame_size = config.get_max_frame_size();
+        let stall_timeout = config.get_stall_timeout();
+        let (read_half, write_half) = stream.into_split();
+
+        Ok(EncryptedStream {
+            reader: EncryptedReadHalf {
+                stream: read_half,
+                decrypt_key,
+                format,
+                max_frame_size,
+                stall_timeout,
+                clock: clock.clone(),
+                counters: None,
+            },
+            writer: EncryptedWriteHalf {
+                stream: BufWriter::new(write_half),
+                encrypt_key,
+                format,
+                max_frame_size,
+                stall_timeout,
+                clock,
+                counters: None,
+            },
+        })
     }
 }
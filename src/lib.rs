@@ -1,9 +1,13 @@
+pub mod clock;
+pub mod codec;
 pub mod config;
 
-mod crypto;
+pub mod crypto;
+pub mod metrics;
 mod networking;
 pub mod transfer;
 
 pub mod client;
 pub mod protocol;
+pub mod receiver;
 pub mod server;
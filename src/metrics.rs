@@ -0,0 +1,91 @@
+use crate::crypto::TrafficCounters;
+use std::{
+    fmt::Write,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+/// Totals over every connection a server has accepted.
+#[derive(Debug, Default)]
+pub struct ServerMetrics {
+    /// Shared by the streams of every session.
+    pub traffic: Arc<TrafficCounters>,
+    pub connections: AtomicU64,
+    pub connection_errors: AtomicU64,
+    pub handshake_failures: AtomicU64,
+}
+
+impl ServerMetrics {
+    pub(crate) fn increment(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Renders the metrics in the Prometheus text exposition format.
+    pub fn render(&self, active_sessions: usize) -> String {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+
+        let metrics = [
+            (
+                "pneumatic_active_sessions",
+                "gauge",
+                "Clients that are currently connected.",
+                active_sessions as u64,
+            ),
+            (
+                "pneumatic_connections_total",
+                "counter",
+                "Connections that completed the handshake.",
+                load(&self.connections),
+            ),
+            (
+                "pneumatic_connection_errors_total",
+                "counter",
+                "Sessions that ended with an error rather than a disconnect.",
+                load(&self.connection_errors),
+            ),
+            (
+                "pneumatic_handshake_failures_total",
+                "counter",
+                "Connections that failed or timed out during the handshake.",
+                load(&self.handshake_failures),
+            ),
+            (
+                "pneumatic_bytes_sent_total",
+                "counter",
+                "Bytes sent in frames, including frame headers.",
+                load(&self.traffic.bytes_sent),
+            ),
+            (
+                "pneumatic_bytes_received_total",
+                "counter",
+                "Bytes received in frames, including frame headers.",
+                load(&self.traffic.bytes_received),
+            ),
+            (
+                "pneumatic_frames_sent_total",
+                "counter",
+                "Frames sent.",
+                load(&self.traffic.frames_sent),
+            ),
+            (
+                "pneumatic_frames_received_total",
+                "counter",
+                "Frames received.",
+                load(&self.traffic.frames_received),
+            ),
+        ];
+
+        let mut text = String::new();
+
+        for (name, kind, help, value) in metrics.iter() {
+            // Writing to a String can't fail.
+            let _ = writeln!(text, "# HELP {} {}", name, help);
+            let _ = writeln!(text, "# TYPE {} {}", name, kind);
+            let _ = writeln!(text, "{} {}", name, value);
+        }
+
+        text
+    }
+}
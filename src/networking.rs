@@ -1,4 +1,8 @@
-use crate::crypto::EncryptedStream;
+use crate::{
+    config::ConnectionConfig,
+    crypto::{EncryptedStream, HandshakeError, ResumptionSecrets},
+    protocol::ResumptionToken,
+};
 use tokio::net::TcpStream;
 
 // TODO: Is this wrapper necessary?
@@ -7,8 +11,29 @@ pub struct Connection {
 }
 
 impl Connection {
-    pub async fn new_encrypted(stream: TcpStream) -> Self {
-        let stream = EncryptedStream::new(stream).await;
-        Connection { stream }
+    pub async fn new_encrypted(
+        stream: TcpStream,
+        config: &ConnectionConfig,
+    ) -> Result<Self, HandshakeError> {
+        let stream = EncryptedStream::new(stream, config).await?;
+        Ok(Connection { stream })
+    }
+
+    pub async fn resume_encrypted(
+        stream: TcpStream,
+        config: &ConnectionConfig,
+        token: &ResumptionToken,
+    ) -> Result<Self, HandshakeError> {
+        let stream = EncryptedStream::resume(stream, config, token).await?;
+        Ok(Connection { stream })
+    }
+
+    pub async fn accept_encrypted(
+        stream: TcpStream,
+        config: &ConnectionConfig,
+        secrets: &dyn ResumptionSecrets,
+    ) -> Result<Self, HandshakeError> {
+        let stream = EncryptedStream::accept(stream, config, secrets).await?;
+        Ok(Connection { stream })
     }
 }
@@ -1,20 +1,49 @@
 use derive_more::From;
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::path::PathBuf;
 
 pub const PROTOCOL_VERSION: u32 = 1;
 
 pub trait ReqRes {
-    type Response: Serialize;
+    type Response: Serialize + DeserializeOwned;
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
 pub struct Greeting {
     pub protocol_version: u32,
+    /// How many `FileChunk`s the client is willing to have in flight before
+    /// it acknowledges them with a `CreditUpdate`. `None` disables flow
+    /// control.
+    pub receive_window: Option<u32>,
 }
 
-#[derive(Serialize, Deserialize)]
+pub(crate) const SESSION_ID_LEN: usize = 16;
+pub(crate) const SESSION_SECRET_LEN: usize = 32;
+
+/// Lets a client resume its session on a new connection if the old one is
+/// lost. Opaque to the client.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ResumptionToken(pub(crate) Vec<u8>);
+
+impl ResumptionToken {
+    /// Splits the token into the id of its session and the secret that proves
+    /// it was handed out by the server, if it's well formed.
+    pub(crate) fn split(&self) -> Option<(&[u8], &[u8])> {
+        if self.0.len() != SESSION_ID_LEN + SESSION_SECRET_LEN {
+            return None;
+        }
+
+        Some(self.0.split_at(SESSION_ID_LEN))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
 pub enum GreetingResponse {
-    ProtocolOk,
+    ProtocolOk {
+        resumption_token: ResumptionToken,
+        /// The window the server will respect, which is never zero.
+        receive_window: Option<u32>,
+    },
     UnsupportedProtocol,
 }
 
@@ -22,12 +51,109 @@ impl ReqRes for Greeting {
     type Response = GreetingResponse;
 }
 
-#[derive(Serialize, Deserialize, Debug, From)]
+/// Identifies a file request so that it can be cancelled. Chosen by the
+/// client and unique within a connection.
+pub type RequestId = u64;
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct FileRequest {
+    pub request_id: RequestId,
+    pub relative_path: PathBuf,
+}
+
+/// Asks for `length` bytes of a file starting at `offset`. A range that goes
+/// past the end of the file stops there, but one that starts past it is an
+/// `InvalidRange` error.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct FileRangeRequest {
+    pub request_id: RequestId,
+    pub relative_path: PathBuf,
+    pub offset: u64,
+    pub length: u64,
+}
+
+/// Asks the server to stop sending a requested file, or to drop the request if
+/// it hasn't been started yet.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct CancelRequest {
+    pub request_id: RequestId,
+}
+
+/// Lets the server send `frames` more `FileChunk`s, once the client has
+/// processed that many. Other responses don't count against the window.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CreditUpdate {
+    pub frames: u32,
+}
+
+/// How much of a file the client had received when its connection was lost.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ResumeOffset {
+    pub request_id: RequestId,
+    pub received: u64,
+}
+
+/// Sent instead of a greeting to continue a session whose connection was
+/// lost. If a file was being sent, it continues from `received`, since
+/// whatever was in flight when the connection dropped never arrived.
+///
+/// The new connection's keys are normally derived from the token during the
+/// handshake (see `Client::reconnect`), so the key exchange is skipped.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct ResumeRequest {
+    pub token: ResumptionToken,
+    pub received: Option<ResumeOffset>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub enum ResumeResponse {
+    Resumed {
+        /// The window agreed on in the session's greeting, which still
+        /// applies.
+        receive_window: Option<u32>,
+    },
+    /// The token is unknown, expired, or belongs to a session that ended
+    /// cleanly. The client has to start over.
+    Rejected,
+}
+
+impl ReqRes for ResumeRequest {
+    type Response = ResumeResponse;
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, From)]
 pub enum ClientMessage {
     Greeting(Greeting),
+    Resume(ResumeRequest),
+    #[from(ignore)]
+    ListFiles,
+    RequestFile(FileRequest),
+    RequestFileRange(FileRangeRequest),
+    Cancel(CancelRequest),
+    Credit(CreditUpdate),
     #[from(ignore)]
     Disconnect,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    NotFound,
+    PermissionDenied,
+    IoError,
+    InvalidRange,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
-pub enum ServerResponse {}
+pub enum ServerResponse {
+    FileList(Vec<PathBuf>),
+    /// A part of a requested file. Followed by more chunks or `FileEnd`.
+    FileChunk(Vec<u8>),
+    FileEnd,
+    Error(ErrorCode),
+    /// The request was cancelled and no more chunks will be sent for it. Sent
+    /// as soon as the cancellation is handled, so it may arrive between the
+    /// chunks of another file.
+    Cancelled(RequestId),
+    /// The request to cancel had already been completed (or never existed).
+    AlreadyCompleted(RequestId),
+}
@@ -0,0 +1,259 @@
+use crate::transfer::{hash_file, timestamps_match, FileMetadata};
+use serde::{Deserialize, Serialize};
+use std::{
+    ffi::OsString,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use thiserror::Error;
+use tokio::{fs::File, io::AsyncWriteExt};
+
+const PARTIAL_FILE_EXTENSION: &str = "pneumatic-part";
+
+#[derive(Debug, Error)]
+pub enum ReceiveError {
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("{path:?} is incomplete: expected {expected} bytes, received {actual}")]
+    SizeMismatch {
+        path: PathBuf,
+        expected: u64,
+        actual: u64,
+    },
+    #[error("{path:?} is corrupt: its hash doesn't match the expected one")]
+    HashMismatch { path: PathBuf },
+}
+
+/// Returns the sibling path a file is written to while it's being received,
+/// e.g. `foo/bar.txt` -> `foo/bar.txt.pneumatic-part`.
+pub fn partial_path(final_path: &Path) -> PathBuf {
+    let mut file_name = final_path.file_name().unwrap_or_default().to_owned();
+    file_name.push(".");
+    file_name.push(PARTIAL_FILE_EXTENSION);
+    final_path.with_file_name(file_name)
+}
+
+/// What to do when a received file already exists at its destination.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// Replace the existing file.
+    #[default]
+    Overwrite,
+    /// Keep the existing file and discard the incoming one.
+    Skip,
+    /// Keep the existing file and write the incoming one next to it, e.g.
+    /// `report.txt` -> `report (1).txt`.
+    RenameIncoming,
+    /// Replace the existing file only if the incoming one was modified more
+    /// recently. Times within the mtime tolerance of each other count as the
+    /// same. If either modification time is unknown, the existing file is
+    /// kept.
+    NewerWins,
+}
+
+/// Decides where `file` should be written under `destination`, according to
+/// `policy`. Returns `None` if the file should be skipped. `mtime_tolerance`
+/// is used by `NewerWins`, see `ServerConfig::mtime_tolerance_ms`.
+pub async fn resolve_conflict(
+    destination: &Path,
+    file: &FileMetadata,
+    policy: ConflictPolicy,
+    mtime_tolerance: Duration,
+) -> Result<Option<PathBuf>, ReceiveError> {
+    let path = destination.join(&file.relative_path);
+
+    let existing = match tokio::fs::metadata(&path).await {
+        Ok(existing) => existing,
+        Err(error) if error.kind() == ErrorKind::NotFound => return Ok(Some(path)),
+        Err(error) => return Err(error.into()),
+    };
+
+    match policy {
+        ConflictPolicy::Overwrite => Ok(Some(path)),
+        ConflictPolicy::Skip => Ok(None),
+        ConflictPolicy::RenameIncoming => free_path(&path).await.map(Some),
+        ConflictPolicy::NewerWins => {
+            let incoming = file.modified_at;
+            let existing = existing.modified().ok();
+
+            match (incoming, existing) {
+                (Some(newer), Some(older))
+                    if newer > older && !timestamps_match(incoming, existing, mtime_tolerance) =>
+                {
+                    Ok(Some(path))
+                }
+                _ => Ok(None),
+            }
+        }
+    }
+}
+
+/// Finds the first of `name (1).ext`, `name (2).ext`, ... that doesn't exist.
+async fn free_path(path: &Path) -> Result<PathBuf, ReceiveError> {
+    let stem = path.file_stem().unwrap_or_default();
+    let extension = path.extension();
+
+    for i in 1.. {
+        let mut file_name = OsString::from(stem);
+        file_name.push(format!(" ({})", i));
+
+        if let Some(extension) = extension {
+            file_name.push(".");
+            file_name.push(extension);
+        }
+
+        let candidate = path.with_file_name(file_name);
+
+        match tokio::fs::symlink_metadata(&candidate).await {
+            Err(error) if error.kind() == ErrorKind::NotFound => return Ok(candidate),
+            Err(error) => return Err(error.into()),
+            Ok(_) => {}
+        }
+    }
+
+    unreachable!()
+}
+
+/// Recreates an empty directory from the transfer plan under `destination`.
+pub async fn create_directory(
+    destination: &Path,
+    directory: &FileMetadata,
+) -> Result<PathBuf, ReceiveError> {
+    let path = destination.join(&directory.relative_path);
+    tokio::fs::create_dir_all(&path).await?;
+    Ok(path)
+}
+
+/// A file that is being received. Data is written to a `.pneumatic-part` file
+/// next to the final path, and only renamed into place by `commit` once the
+/// content has been verified. If the transfer is interrupted, the final path
+/// is never touched and only the partial file is left behind.
+pub struct PartialFile {
+    file: File,
+    final_path: PathBuf,
+    partial_path: PathBuf,
+    bytes_written: u64,
+    hasher: Option<blake3::Hasher>,
+}
+
+impl PartialFile {
+    pub async fn create(final_path: impl AsRef<Path>) -> Result<Self, ReceiveError> {
+        Self::open(final_path.as_ref(), None).await
+    }
+
+    /// Like `create`, but hashes the content as it's written, so that `commit`
+    /// doesn't have to read the file back to verify its hash.
+    pub async fn create_hashed(final_path: impl AsRef<Path>) -> Result<Self, ReceiveError> {
+        Self::open(final_path.as_ref(), Some(blake3::Hasher::new())).await
+    }
+
+    /// Creates the partial file for `file` under `destination`, resolving a
+    /// conflict with an existing file according to `policy`. Returns `None` if
+    /// the file should be skipped. If the plan has a hash for the file, the
+    /// content is hashed as it's written.
+    pub async fn create_with_policy(
+        destination: &Path,
+        file: &FileMetadata,
+        policy: ConflictPolicy,
+        mtime_tolerance: Duration,
+    ) -> Result<Option<Self>, ReceiveError> {
+        let final_path = match resolve_conflict(destination, file, policy, mtime_tolerance).await? {
+            Some(final_path) => final_path,
+            None => return Ok(None),
+        };
+
+        let hasher = file.content_hash.map(|_| blake3::Hasher::new());
+        Self::open(&final_path, hasher).await.map(Some)
+    }
+
+    async fn open(final_path: &Path, hasher: Option<blake3::Hasher>) -> Result<Self, ReceiveError> {
+        let final_path = final_path.to_owned();
+        let partial_path = partial_path(&final_path);
+
+        if let Some(parent) = final_path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+
+        let file = File::create(&partial_path).await?;
+
+        Ok(PartialFile {
+            file,
+            final_path,
+            partial_path,
+            bytes_written: 0,
+            hasher,
+        })
+    }
+
+    pub fn partial_path(&self) -> &Path {
+        &self.partial_path
+    }
+
+    pub async fn write(&mut self, data: &[u8]) -> Result<(), ReceiveError> {
+        self.file.write_all(data).await?;
+        self.bytes_written += data.len() as u64;
+
+        if let Some(hasher) = &mut self.hasher {
+            hasher.update(data);
+        }
+
+        Ok(())
+    }
+
+    /// Verifies the size of the received content and, if `expected_hash` is
+    /// given, its BLAKE3 hash, then flushes it to disk and atomically moves it
+    /// to its final path. On failure the partial file is left in place.
+    pub async fn commit(
+        mut self,
+        expected_size: u64,
+        expected_hash: Option<[u8; 32]>,
+    ) -> Result<PathBuf, ReceiveError> {
+        if self.bytes_written != expected_size {
+            return Err(ReceiveError::SizeMismatch {
+                path: self.final_path,
+                expected: expected_size,
+                actual: self.bytes_written,
+            });
+        }
+
+        self.file.flush().await?;
+        self.file.sync_all().await?;
+        drop(self.file);
+
+        if let Some(expected_hash) = expected_hash {
+            let actual_hash = match self.hasher {
+                Some(hasher) => *hasher.finalize().as_bytes(),
+                None => hash_file(&self.partial_path).await?,
+            };
+
+            if actual_hash != expected_hash {
+                return Err(ReceiveError::HashMismatch {
+                    path: self.final_path,
+                });
+            }
+        }
+
+        tokio::fs::rename(&self.partial_path, &self.final_path).await?;
+        sync_parent_directory(&self.final_path).await?;
+
+        Ok(self.final_path)
+    }
+}
+
+// On Unix a rename is only durable once the directory containing it has been
+// synced as well.
+#[cfg(unix)]
+async fn sync_parent_directory(path: &Path) -> Result<(), std::io::Error> {
+    let parent = match path.parent() {
+        Some(parent) if parent != Path::new("") => parent,
+        _ => Path::new("."),
+    };
+
+    File::open(parent).await?.sync_all().await
+}
+
+#[cfg(not(unix))]
+async fn sync_parent_directory(_path: &Path) -> Result<(), std::io::Error> {
+    Ok(())
+}
@@ -1,16 +1,47 @@
 use crate::{
+    clock::{Clock, TokioClock},
+    config::ServerConfig,
+    crypto::{EncryptedWriteHalf, ResumptionSecrets, StreamError},
+    metrics::ServerMetrics,
     networking::Connection,
-    protocol::{ClientMessage, GreetingResponse, ReqRes},
+    protocol::{
+        CancelRequest, ClientMessage, CreditUpdate, ErrorCode, FileRangeRequest, FileRequest,
+        GreetingResponse, ReqRes, RequestId, ResumeRequest, ResumeResponse, ResumptionToken,
+        ServerResponse, SESSION_ID_LEN, SESSION_SECRET_LEN,
+    },
+    transfer::VirtualFile,
+};
+use async_trait::async_trait;
+use futures::{stream, FutureExt, Stream, StreamExt};
+use glob::{MatchOptions, Pattern};
+use ring::{
+    constant_time::verify_slices_are_equal,
+    rand::{SecureRandom, SystemRandom},
+};
+use std::{
+    collections::{HashMap, VecDeque},
+    io::{self, Seek, SeekFrom},
+    net::SocketAddr,
+    path::{Component, Path, PathBuf},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::sync::{mpsc::error::TryRecvError, RwLock};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    net::{TcpListener, TcpStream},
 };
-use std::{collections::HashMap, net::SocketAddr, sync::Arc};
-use tokio::net::TcpListener;
-use tokio::sync::RwLock;
 use tokio::{select, task};
 
+const FILE_CHUNK_SIZE: usize = 64 * 1024;
+
 pub trait FileSystem: Send + Sync {
     fn list_files(&self, path: &std::path::Path, output: &mut Vec<std::path::PathBuf>);
+    fn open_file(&self, path: &std::path::Path) -> Result<std::fs::File, ErrorCode>;
 }
 
+#[derive(Default)]
 pub struct MockFileSystem {}
 
 impl MockFileSystem {
@@ -20,119 +51,1197 @@ impl MockFileSystem {
 }
 
 impl FileSystem for MockFileSystem {
-    fn list_files(&self, path: &std::path::Path, output: &mut Vec<std::path::PathBuf>) {
+    fn list_files(&self, _path: &std::path::Path, _output: &mut Vec<std::path::PathBuf>) {
         todo!()
     }
+
+    fn open_file(&self, _path: &std::path::Path) -> Result<std::fs::File, ErrorCode> {
+        Err(ErrorCode::NotFound)
+    }
+}
+
+/// Decides which paths the server is willing to serve, based on the
+/// `allowed_paths` and `denied_paths` globs of `ServerConfig`. A path must
+/// match an allow pattern (if there are any) and must not match any deny
+/// pattern; deny always wins.
+///
+/// Deny patterns ignore case, since on a case-insensitive filesystem
+/// `secret.KEY` opens `secret.key`.
+pub struct PathFilter {
+    allow: Vec<Pattern>,
+    deny: Vec<Pattern>,
+}
+
+impl PathFilter {
+    pub fn from_config(config: &ServerConfig) -> Result<Self, glob::PatternError> {
+        let compile = |patterns: &[String]| {
+            patterns
+                .iter()
+                .map(|pattern| Pattern::new(pattern))
+                .collect::<Result<Vec<_>, _>>()
+        };
+
+        Ok(PathFilter {
+            allow: compile(&config.allowed_paths)?,
+            deny: compile(&config.denied_paths)?,
+        })
+    }
+
+    pub fn is_allowed(&self, relative_path: &Path) -> bool {
+        let ignore_case = MatchOptions {
+            case_sensitive: false,
+            ..MatchOptions::new()
+        };
+
+        if self
+            .deny
+            .iter()
+            .any(|pattern| pattern.matches_path_with(relative_path, ignore_case))
+        {
+            return false;
+        }
+
+        self.allow.is_empty()
+            || self
+                .allow
+                .iter()
+                .any(|pattern| pattern.matches_path(relative_path))
+    }
 }
 
-struct ServerConnection(Connection);
+/// Serves files from a directory on disk. All paths are relative to `root`.
+pub struct DiskFileSystem {
+    root: PathBuf,
+    filter: PathFilter,
+}
+
+impl DiskFileSystem {
+    pub fn new(root: impl AsRef<Path>, config: &ServerConfig) -> Result<Self, glob::PatternError> {
+        Ok(DiskFileSystem {
+            root: root.as_ref().to_owned(),
+            filter: PathFilter::from_config(config)?,
+        })
+    }
+
+    fn list_files_recursively(&self, relative_path: &Path, output: &mut Vec<PathBuf>) {
+        let entries = match std::fs::read_dir(self.root.join(relative_path)) {
+            Ok(entries) => entries,
+            Err(_) => return,
+        };
+
+        for entry in entries.flatten() {
+            let path = relative_path.join(entry.file_name());
+
+            match entry.file_type() {
+                Ok(file_type) if file_type.is_dir() => self.list_files_recursively(&path, output),
+                Ok(file_type) => {
+                    // A symlink is only listed if what it points to can be
+                    // served.
+                    let servable = if file_type.is_symlink() {
+                        self.resolve(&path).is_ok()
+                    } else {
+                        self.filter.is_allowed(&path)
+                    };
+
+                    if servable {
+                        output.push(path);
+                    }
+                }
+                Err(_) => {}
+            }
+        }
+    }
+
+    /// Returns the real path of the file at `path`, if it may be served.
+    ///
+    /// Symlinks are followed, so the file they point to must be inside the
+    /// root and pass the filter as well.
+    fn resolve(&self, path: &Path) -> Result<PathBuf, ErrorCode> {
+        if !is_contained(path) || !self.filter.is_allowed(path) {
+            return Err(ErrorCode::PermissionDenied);
+        }
+
+        let root = self.root.canonicalize().map_err(to_error_code)?;
+        let resolved = root.join(path).canonicalize().map_err(to_error_code)?;
+
+        match resolved.strip_prefix(&root) {
+            Ok(target) if self.filter.is_allowed(target) => Ok(resolved),
+            _ => Err(ErrorCode::PermissionDenied),
+        }
+    }
+}
+
+// Only plain relative paths are accepted, so that requests can't escape the
+// root with `..` or an absolute path.
+fn is_contained(path: &Path) -> bool {
+    path.components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+}
+
+fn to_error_code(error: io::Error) -> ErrorCode {
+    match error.kind() {
+        io::ErrorKind::NotFound => ErrorCode::NotFound,
+        io::ErrorKind::PermissionDenied => ErrorCode::PermissionDenied,
+        _ => ErrorCode::IoError,
+    }
+}
+
+impl FileSystem for DiskFileSystem {
+    fn list_files(&self, path: &Path, output: &mut Vec<PathBuf>) {
+        if is_contained(path) {
+            self.list_files_recursively(path, output);
+        }
+    }
+
+    fn open_file(&self, path: &Path) -> Result<std::fs::File, ErrorCode> {
+        std::fs::File::open(self.resolve(path)?).map_err(to_error_code)
+    }
+}
+
+type MessageStream = Pin<Box<dyn Stream<Item = Result<ClientMessage, StreamError>> + Send>>;
+
+/// The server's end of a connection. Messages are read through a stream that
+/// keeps a partially received frame across polls, so `receive` can be used in
+/// `select!` and `try_receive` without losing data.
+struct ServerConnection {
+    messages: MessageStream,
+    writer: EncryptedWriteHalf,
+}
 
 impl ServerConnection {
-    pub fn new(connetion: Connection) -> Self {
-        ServerConnection(connetion)
+    pub fn new(connection: Connection) -> Self {
+        let (reader, writer) = connection.stream.split();
+
+        let messages = stream::unfold(
+            (reader, Vec::new()),
+            |(mut reader, mut buffer)| async move {
+                let message = reader.receive(&mut buffer).await;
+                Some((message, (reader, buffer)))
+            },
+        );
+
+        ServerConnection {
+            messages: Box::pin(messages),
+            writer,
+        }
+    }
+
+    pub async fn receive(&mut self) -> Result<ClientMessage, StreamError> {
+        // The stream never ends; errors are returned as items instead.
+        self.messages.next().await.unwrap()
+    }
+
+    /// Returns a message if one has already arrived, without waiting.
+    pub fn try_receive(&mut self) -> Option<Result<ClientMessage, StreamError>> {
+        self.messages.next().now_or_never().flatten()
+    }
+
+    pub async fn respond<S: ReqRes>(
+        &mut self,
+        _req: S,
+        res: S::Response,
+    ) -> Result<(), StreamError> {
+        self.writer.send(&res).await
+    }
+
+    pub async fn send(&mut self, response: &ServerResponse) -> Result<(), StreamError> {
+        self.writer.send(response).await
+    }
+
+    pub async fn flush(&mut self) -> Result<(), StreamError> {
+        self.writer.flush().await
+    }
+}
+
+/// Where the contents of a transfer come from.
+enum TransferSource {
+    Disk(tokio::fs::File),
+    Virtual {
+        reader: Box<dyn AsyncRead + Send + Sync + Unpin>,
+        size: u64,
+    },
+}
+
+/// A file that is being sent to the client.
+struct ActiveTransfer {
+    request_id: RequestId,
+    relative_path: PathBuf,
+    source: TransferSource,
+    /// Where in the file the transfer starts.
+    offset: u64,
+    /// How much of the file to send, if not all of it.
+    length: Option<u64>,
+    /// Bytes of the file sent so far, counted from `offset`.
+    sent: u64,
+    /// Read ahead of what has been sent, so that the end of the file is found
+    /// without waiting for credit to send another chunk.
+    next_chunk: Option<Vec<u8>>,
+}
+
+impl ActiveTransfer {
+    async fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let position = self.offset + self.sent;
+
+        let buffer = match self.length {
+            Some(length) => {
+                let remaining = (length - self.sent).min(buffer.len() as u64);
+                &mut buffer[..remaining as usize]
+            }
+            None => buffer,
+        };
+
+        match &mut self.source {
+            TransferSource::Disk(file) => file.read(buffer).await,
+            TransferSource::Virtual { reader, size } => {
+                let remaining = *size - position;
+                let limit = buffer.len().min(remaining as usize);
+
+                if limit == 0 {
+                    return Ok(0);
+                }
+
+                match reader.read(&mut buffer[..limit]).await? {
+                    0 => Err(io::ErrorKind::UnexpectedEof.into()),
+                    read => Ok(read),
+                }
+            }
+        }
+    }
+
+    /// Moves back to `offset`, which must not be past what has been sent.
+    /// Virtual files can't be rewound, so for them it has to be exactly
+    /// where the transfer already is.
+    async fn rewind_to(&mut self, offset: u64) -> io::Result<()> {
+        match &mut self.source {
+            TransferSource::Disk(file) => {
+                self.next_chunk = None;
+                file.seek(SeekFrom::Start(self.offset + offset))
+                    .await
+                    .map(|_| ())
+            }
+            TransferSource::Virtual { .. } if offset == self.sent => Ok(()),
+            TransferSource::Virtual { .. } => Err(io::ErrorKind::InvalidInput.into()),
+        }?;
+
+        self.sent = offset;
+        Ok(())
     }
 
-    pub async fn receive(&mut self, buffer: &mut Vec<u8>) -> ClientMessage {
-        self.0.stream.receive_bincode(buffer).await
+    /// The request that started this transfer, for sending it again from the
+    /// start.
+    fn into_request(self) -> ClientMessage {
+        match self.length {
+            Some(length) => ClientMessage::RequestFileRange(FileRangeRequest {
+                request_id: self.request_id,
+                relative_path: self.relative_path,
+                offset: self.offset,
+                length,
+            }),
+            None => ClientMessage::RequestFile(FileRequest {
+                request_id: self.request_id,
+                relative_path: self.relative_path,
+            }),
+        }
+    }
+}
+
+enum TransferStatus {
+    InProgress,
+    /// The next chunk is ready but there's no credit to send it.
+    Blocked,
+    Finished,
+    Failed,
+}
+
+/// The client's receive window, negotiated in the greeting.
+#[derive(Clone, Copy)]
+struct FlowControl {
+    window: u32,
+    /// Chunks that can be sent before the client acknowledges more.
+    credits: u32,
+}
+
+impl FlowControl {
+    fn new(window: u32) -> Self {
+        FlowControl {
+            window,
+            credits: window,
+        }
     }
 
-    pub async fn respond<S: ReqRes>(&mut self, _req: S, res: S::Response) {
-        self.0.stream.send_bincode(&res).await;
+    fn grant(&mut self, credit: CreditUpdate) {
+        // A client can't have acknowledged more than was in flight.
+        self.credits = self.credits.saturating_add(credit.frames).min(self.window);
     }
 }
 
+type SessionId = [u8; SESSION_ID_LEN];
+
+/// Everything a session needs to carry on after the client reconnects.
+struct SessionState {
+    id: SessionId,
+    // Proves that whoever presents the resumption token got it from us.
+    secret: [u8; SESSION_SECRET_LEN],
+    /// Messages waiting for the current file to finish.
+    pending: VecDeque<ClientMessage>,
+    active: Option<ActiveTransfer>,
+    /// The last file that was sent in full. It's kept open since the end of
+    /// it may still have been in flight if the connection is lost.
+    last_finished: Option<ActiveTransfer>,
+    /// `None` if the client didn't ask for flow control.
+    flow_control: Option<FlowControl>,
+    /// Whether the client has been given a resumption token. Only then is
+    /// there any point in keeping the session around if the connection is
+    /// lost.
+    resumable: bool,
+}
+
+impl SessionState {
+    fn new() -> Self {
+        let rng = SystemRandom::new();
+        let mut id = [0u8; SESSION_ID_LEN];
+        let mut secret = [0u8; SESSION_SECRET_LEN];
+        rng.fill(&mut id).unwrap();
+        rng.fill(&mut secret).unwrap();
+
+        SessionState {
+            id,
+            secret,
+            pending: VecDeque::new(),
+            active: None,
+            last_finished: None,
+            flow_control: None,
+            resumable: false,
+        }
+    }
+
+    fn has_credit(&self) -> bool {
+        !matches!(self.flow_control, Some(flow) if flow.credits == 0)
+    }
+
+    /// Whether the active file can make progress: either there's credit for
+    /// another chunk, or it's not known yet whether there is one.
+    fn can_send(&self) -> bool {
+        match &self.active {
+            Some(transfer) => self.has_credit() || transfer.next_chunk.is_none(),
+            None => false,
+        }
+    }
+
+    fn resumption_token(&self) -> ResumptionToken {
+        ResumptionToken([&self.id[..], &self.secret[..]].concat())
+    }
+}
+
+/// A session whose connection was lost, waiting to be resumed.
+struct SuspendedSession {
+    state: SessionState,
+    expires_at: Instant,
+}
+
+/// Shared by every session of a server.
+struct SessionContext {
+    server: Arc<RwLock<Server>>,
+    fs: Arc<dyn FileSystem>,
+    clock: Arc<dyn Clock>,
+    metrics: Arc<ServerMetrics>,
+    idle_timeout: Duration,
+    resumption_ttl: Duration,
+    max_suspended_sessions: usize,
+}
+
+#[derive(Debug)]
+enum SessionControl {
+    Close,
+}
+
 pub struct Session {
     address: SocketAddr,
+    control: tokio::sync::mpsc::Sender<SessionControl>,
 }
 
 type SharedSession = Arc<RwLock<Session>>;
 
 pub struct Server {
-    fs: Box<dyn FileSystem>,
+    fs: Arc<dyn FileSystem>,
+    clock: Arc<dyn Clock>,
     pub sessions: HashMap<SocketAddr, SharedSession>,
+    suspended: HashMap<SessionId, SuspendedSession>,
+    metrics: Arc<ServerMetrics>,
+    // Sessions take virtual files out of here while holding only a read lock.
+    virtual_files: Mutex<HashMap<PathBuf, VirtualFile>>,
 }
 
 #[derive(Debug)]
 enum ControlMessage {
+    #[allow(dead_code)]
     Shutdown,
     Disconnect(SocketAddr),
 }
 
+const MIN_ACCEPT_BACKOFF: Duration = Duration::from_millis(10);
+const MAX_ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Decides how long the accept loop waits after a failed `accept`.
+///
+/// Most accept errors are either about a single incoming connection (reset,
+/// aborted) or about running out of resources (e.g. EMFILE). Retrying those
+/// immediately would make the loop spin, so the delay doubles with every
+/// consecutive error. `InvalidInput` means the listening socket itself is
+/// unusable, which is fatal.
+pub struct AcceptBackoff {
+    consecutive_errors: u32,
+}
+
+impl AcceptBackoff {
+    pub fn new() -> Self {
+        AcceptBackoff {
+            consecutive_errors: 0,
+        }
+    }
+
+    pub fn on_success(&mut self) {
+        self.consecutive_errors = 0;
+    }
+
+    /// Returns how long to wait before accepting again, or `None` if the error
+    /// is fatal.
+    pub fn on_error(&mut self, error: &io::Error) -> Option<Duration> {
+        if error.kind() == io::ErrorKind::InvalidInput {
+            return None;
+        }
+
+        let delay = MIN_ACCEPT_BACKOFF * 2u32.pow(self.consecutive_errors.min(16));
+        self.consecutive_errors += 1;
+
+        Some(delay.min(MAX_ACCEPT_BACKOFF))
+    }
+}
+
+impl Default for AcceptBackoff {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Lets a client that lost its connection skip the key exchange when it
+// reconnects. The session itself is only taken out of `suspended` once the
+// client sends a `ResumeRequest` over the new connection.
+#[async_trait]
+impl ResumptionSecrets for RwLock<Server> {
+    async fn resumption_secret(&self, session_id: &[u8]) -> Option<Vec<u8>> {
+        let server = self.read().await;
+        let now = server.clock.now();
+
+        server
+            .suspended
+            .get(session_id)
+            .filter(|session| session.expires_at > now)
+            .map(|session| session.state.secret.to_vec())
+    }
+}
+
+/// A source of incoming connections for the accept loop.
+#[async_trait]
+pub trait Listener: Send + 'static {
+    async fn accept(&mut self) -> io::Result<(TcpStream, SocketAddr)>;
+}
+
+#[async_trait]
+impl Listener for TcpListener {
+    async fn accept(&mut self) -> io::Result<(TcpStream, SocketAddr)> {
+        TcpListener::accept(self).await
+    }
+}
+
 impl Server {
+    /// Binds a listener using the listen backlog from `config`.
+    pub fn bind(address: SocketAddr, config: &ServerConfig) -> io::Result<TcpListener> {
+        let builder = match address {
+            SocketAddr::V4(_) => net2::TcpBuilder::new_v4()?,
+            SocketAddr::V6(_) => net2::TcpBuilder::new_v6()?,
+        };
+
+        let listener = builder
+            .reuse_address(true)?
+            .bind(address)?
+            .listen(config.get_listen_backlog() as i32)?;
+
+        TcpListener::from_std(listener)
+    }
+
     async fn handle_client(
         mut server_channel: tokio::sync::mpsc::Sender<ControlMessage>,
         mut connection: ServerConnection,
         session: SharedSession,
+        mut session_control: tokio::sync::mpsc::Receiver<SessionControl>,
+        context: SessionContext,
     ) {
         let session_reader = session.read().await;
-        let address = session_reader.address.clone();
+        let address = session_reader.address;
         drop(session_reader);
 
-        let mut message_buffer = Vec::new();
+        let mut state = SessionState::new();
+        let result =
+            Self::serve_client(&mut connection, &mut session_control, &mut state, &context).await;
+
+        match result {
+            Ok(()) => println!("Client {} disconnecting.", address),
+            Err(error) => {
+                println!("Connection to {} failed: {}", address, error);
+                ServerMetrics::increment(&context.metrics.connection_errors);
+
+                // The client may just have lost its connection, so keep the
+                // session around for it to resume. A client that sent
+                // something invalid has nothing to come back to.
+                if state.resumable && error.is_connection_lost() {
+                    let expires_at = context.clock.now() + context.resumption_ttl;
+                    context.server.write().await.suspend(
+                        state,
+                        expires_at,
+                        context.max_suspended_sessions,
+                    );
+                }
+            }
+        }
+
+        server_channel
+            .send(ControlMessage::Disconnect(address))
+            .await
+            .unwrap();
+    }
+
+    /// Serves requests until the client disconnects or the session is closed.
+    ///
+    /// Files are sent one at a time, a chunk per iteration, and other messages
+    /// are queued until the current file is done so that responses don't end
+    /// up in the middle of it. Cancellations are handled between chunks, which
+    /// lets a client stop a large file promptly.
+    ///
+    /// If the client asked for flow control, chunks stop once its window is
+    /// used up and the session waits for credit as if it were idle. Other
+    /// messages don't count against the window.
+    ///
+    /// A session that hasn't sent anything for the idle timeout while no file
+    /// is being sent to it is closed.
+    async fn serve_client(
+        connection: &mut ServerConnection,
+        session_control: &mut tokio::sync::mpsc::Receiver<SessionControl>,
+        state: &mut SessionState,
+        context: &SessionContext,
+    ) -> Result<(), StreamError> {
+        let clock = context.clock.as_ref();
+        let mut last_activity = clock.now();
 
         loop {
-            let message = connection.receive(&mut message_buffer).await;
+            let message = if state.can_send() {
+                match session_control.try_recv() {
+                    Ok(SessionControl::Close) | Err(TryRecvError::Closed) => return Ok(()),
+                    Err(TryRecvError::Empty) => {}
+                }
+
+                connection.try_receive().transpose()?
+            } else if state.active.is_some() || state.pending.is_empty() {
+                connection.flush().await?;
+
+                select! {
+                    message = connection.receive() => Some(message?),
+                    // A closed channel means the session was dropped by the server.
+                    control = session_control.recv() => match control {
+                        Some(SessionControl::Close) | None => return Ok(()),
+                    },
+                    _ = clock.sleep_until(last_activity + context.idle_timeout) => return Ok(()),
+                }
+            } else {
+                None
+            };
+
+            if message.is_some() {
+                last_activity = clock.now();
+            }
 
             match message {
-                ClientMessage::Greeting(greeting) => {
-                    connection
-                        .respond(greeting, GreetingResponse::ProtocolOk)
-                        .await;
+                Some(ClientMessage::Cancel(cancel)) => {
+                    Self::cancel(connection, &mut state.pending, &mut state.active, cancel).await?;
+                }
+                Some(ClientMessage::Credit(credit)) => {
+                    if let Some(flow) = &mut state.flow_control {
+                        flow.grant(credit);
+                    }
+                }
+                Some(ClientMessage::Disconnect) => return Ok(()),
+                Some(message) => state.pending.push_back(message),
+                None => {}
+            }
+
+            if state.active.is_some() && !state.can_send() {
+                continue;
+            }
+
+            let has_credit = state.has_credit();
+
+            if let Some(transfer) = &mut state.active {
+                match Self::send_chunk(connection, transfer, has_credit).await? {
+                    TransferStatus::InProgress => {
+                        if let Some(flow) = &mut state.flow_control {
+                            flow.credits -= 1;
+                        }
+
+                        continue;
+                    }
+                    TransferStatus::Blocked => continue,
+                    TransferStatus::Finished => state.last_finished = state.active.take(),
+                    TransferStatus::Failed => state.active = None,
+                }
+
+                // The client may well take a while to process the file, so the
+                // idle time starts when the file ends.
+                last_activity = clock.now();
+                continue;
+            }
+
+            match state.pending.pop_front() {
+                Some(ClientMessage::Greeting(greeting)) => {
+                    // A window of zero would never let anything through.
+                    state.flow_control = greeting
+                        .receive_window
+                        .map(|window| FlowControl::new(window.max(1)));
+
+                    let response = GreetingResponse::ProtocolOk {
+                        resumption_token: state.resumption_token(),
+                        receive_window: state.flow_control.map(|flow| flow.window),
+                    };
+                    state.resumable = true;
+                    connection.respond(greeting, response).await?;
+                }
+                Some(ClientMessage::Resume(request)) => {
+                    let response = Self::resume(state, &request, context).await;
+                    connection.respond(request, response).await?;
+                }
+                Some(ClientMessage::ListFiles) => {
+                    let mut files = Vec::new();
+                    context.fs.list_files(Path::new(""), &mut files);
+                    files.extend(context.server.read().await.virtual_file_paths());
+                    connection.send(&ServerResponse::FileList(files)).await?;
+                }
+                Some(ClientMessage::RequestFile(request)) => {
+                    state.active = Self::start_transfer(
+                        connection,
+                        context,
+                        request.request_id,
+                        request.relative_path,
+                        None,
+                    )
+                    .await?;
+                }
+                Some(ClientMessage::RequestFileRange(request)) => {
+                    state.active = Self::start_transfer(
+                        connection,
+                        context,
+                        request.request_id,
+                        request.relative_path,
+                        Some((request.offset, request.length)),
+                    )
+                    .await?;
+                }
+                // Cancel, Credit and Disconnect are never queued.
+                Some(ClientMessage::Cancel(_))
+                | Some(ClientMessage::Credit(_))
+                | Some(ClientMessage::Disconnect)
+                | None => {}
+            }
+        }
+    }
+
+    /// Replaces the state of this connection's session with a suspended one.
+    async fn resume(
+        state: &mut SessionState,
+        request: &ResumeRequest,
+        context: &SessionContext,
+    ) -> ResumeResponse {
+        // A session is resumed before anything else happens on a connection.
+        if state.active.is_some() {
+            return ResumeResponse::Rejected;
+        }
+
+        let now = context.clock.now();
+        let suspended = context
+            .server
+            .write()
+            .await
+            .take_suspended(&request.token, now);
+
+        let mut resumed = match suspended {
+            Some(resumed) => resumed,
+            None => return ResumeResponse::Rejected,
+        };
+
+        // Whatever was in flight when the connection dropped was lost, so the
+        // file the client was receiving continues from what actually arrived.
+        let interrupted = match request.received {
+            Some(offset) if Self::is_transfer(&resumed.active, offset.request_id) => resumed
+                .active
+                .take()
+                .map(|transfer| (transfer, offset.received)),
+            Some(offset) if Self::is_transfer(&resumed.last_finished, offset.request_id) => {
+                // We'd already sent all of it, so whatever was started after
+                // it hasn't arrived either and has to start over.
+                if let Some(transfer) = resumed.active.take() {
+                    resumed.pending.push_front(transfer.into_request());
+                }
+
+                resumed
+                    .last_finished
+                    .take()
+                    .map(|transfer| (transfer, offset.received))
+            }
+            Some(_) => return ResumeResponse::Rejected,
+            None => resumed.active.take().map(|transfer| (transfer, 0)),
+        };
+
+        if let Some((mut transfer, received)) = interrupted {
+            if received > transfer.sent {
+                return ResumeResponse::Rejected;
+            }
+
+            if transfer.rewind_to(received).await.is_err() {
+                return ResumeResponse::Rejected;
+            }
+
+            resumed.active = Some(transfer);
+        }
+
+        // Nothing is in flight on the new connection.
+        if let Some(flow) = &mut resumed.flow_control {
+            *flow = FlowControl::new(flow.window);
+        }
+
+        // Anything the client sent after the resume request comes after what
+        // was already queued.
+        resumed.pending.append(&mut state.pending);
+        *state = resumed;
+
+        ResumeResponse::Resumed {
+            receive_window: state.flow_control.as_ref().map(|flow| flow.window),
+        }
+    }
+
+    fn is_transfer(transfer: &Option<ActiveTransfer>, request_id: RequestId) -> bool {
+        matches!(transfer, Some(transfer) if transfer.request_id == request_id)
+    }
+
+    async fn cancel(
+        connection: &mut ServerConnection,
+        pending: &mut VecDeque<ClientMessage>,
+        active: &mut Option<ActiveTransfer>,
+        cancel: CancelRequest,
+    ) -> Result<(), StreamError> {
+        let request_id = cancel.request_id;
+
+        let is_active = Self::is_transfer(active, request_id);
+        let is_pending = |message: &ClientMessage| match message {
+            ClientMessage::RequestFile(request) => request.request_id == request_id,
+            ClientMessage::RequestFileRange(request) => request.request_id == request_id,
+            _ => false,
+        };
+
+        let response = if is_active {
+            // Dropping the transfer closes the file.
+            *active = None;
+            ServerResponse::Cancelled(request_id)
+        } else if let Some(index) = pending.iter().position(is_pending) {
+            pending.remove(index);
+            ServerResponse::Cancelled(request_id)
+        } else {
+            ServerResponse::AlreadyCompleted(request_id)
+        };
+
+        connection.send(&response).await
+    }
+
+    /// Starts sending a file, or the part of it given by `range` (an offset
+    /// and a length), or tells the client why it can't be sent.
+    async fn start_transfer(
+        connection: &mut ServerConnection,
+        context: &SessionContext,
+        request_id: RequestId,
+        relative_path: PathBuf,
+        range: Option<(u64, u64)>,
+    ) -> Result<Option<ActiveTransfer>, StreamError> {
+        let offset = range.map_or(0, |(offset, _)| offset);
+
+        let virtual_file = context
+            .server
+            .read()
+            .await
+            .take_virtual_file(&relative_path, offset);
+
+        let opened = match virtual_file {
+            Ok(Some(file)) => Self::open_virtual(file, offset).await,
+            Err(error) => Err(error),
+            Ok(None) => context
+                .fs
+                .open_file(&relative_path)
+                .and_then(|file| Self::open_disk(file, offset)),
+        };
+
+        match opened {
+            Ok((source, size)) => Ok(Some(ActiveTransfer {
+                request_id,
+                relative_path,
+                source,
+                offset,
+                length: range.map(|(_, length)| length.min(size - offset)),
+                sent: 0,
+                next_chunk: None,
+            })),
+            Err(error) => {
+                connection.send(&ServerResponse::Error(error)).await?;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Seeks `file` to `offset` and returns it along with its size.
+    fn open_disk(mut file: std::fs::File, offset: u64) -> Result<(TransferSource, u64), ErrorCode> {
+        let size = file.metadata().map_err(|_| ErrorCode::IoError)?.len();
+
+        if offset > size {
+            return Err(ErrorCode::InvalidRange);
+        }
+
+        file.seek(SeekFrom::Start(offset))
+            .map_err(|_| ErrorCode::IoError)?;
+
+        let file = tokio::fs::File::from_std(file);
+        Ok((TransferSource::Disk(file), size))
+    }
+
+    /// Like `open_disk`, but since the reader can't seek, everything before
+    /// `offset` is read and thrown away. `take_virtual_file` has already
+    /// checked that `offset` is within the file.
+    async fn open_virtual(
+        file: VirtualFile,
+        offset: u64,
+    ) -> Result<(TransferSource, u64), ErrorCode> {
+        let size = file.size();
+        let mut reader = file.into_reader();
+        let skipped = tokio::io::copy(&mut (&mut reader).take(offset), &mut tokio::io::sink())
+            .await
+            .map_err(|_| ErrorCode::IoError)?;
+
+        if skipped < offset {
+            return Err(ErrorCode::IoError);
+        }
+
+        Ok((TransferSource::Virtual { reader, size }, size))
+    }
+
+    /// Sends the next chunk of `transfer`, or `FileEnd` once all of it has
+    /// been sent. Without `has_credit`, the chunk is only read.
+    async fn send_chunk(
+        connection: &mut ServerConnection,
+        transfer: &mut ActiveTransfer,
+        has_credit: bool,
+    ) -> Result<TransferStatus, StreamError> {
+        let chunk = match transfer.next_chunk.take() {
+            Some(chunk) => chunk,
+            None => {
+                let mut chunk = vec![0u8; FILE_CHUNK_SIZE];
+
+                let read = match transfer.read(&mut chunk).await {
+                    Ok(read) => read,
+                    Err(_) => {
+                        connection
+                            .send(&ServerResponse::Error(ErrorCode::IoError))
+                            .await?;
+                        return Ok(TransferStatus::Failed);
+                    }
+                };
+
+                if read == 0 {
+                    connection.send(&ServerResponse::FileEnd).await?;
+                    return Ok(TransferStatus::Finished);
                 }
-                ClientMessage::Disconnect => {
-                    println!("Client {} disconnecting.", address);
 
-                    server_channel
-                        .send(ControlMessage::Disconnect(address))
-                        .await
-                        .unwrap();
-                    break;
+                chunk.truncate(read);
+                chunk
+            }
+        };
+
+        if !has_credit {
+            transfer.next_chunk = Some(chunk);
+            return Ok(TransferStatus::Blocked);
+        }
+
+        let length = chunk.len() as u64;
+        connection.send(&ServerResponse::FileChunk(chunk)).await?;
+        transfer.sent += length;
+        Ok(TransferStatus::InProgress)
+    }
+
+    /// Makes `file` available to clients under its relative path, in addition
+    /// to the files of the server's `FileSystem`. It is listed until a client
+    /// requests it, and takes precedence over a file on disk with the same
+    /// path.
+    pub fn add_virtual_file(&mut self, file: VirtualFile) {
+        self.virtual_files
+            .get_mut()
+            .unwrap()
+            .insert(file.relative_path().to_owned(), file);
+    }
+
+    fn virtual_file_paths(&self) -> Vec<PathBuf> {
+        let mut paths: Vec<_> = self.virtual_files.lock().unwrap().keys().cloned().collect();
+        paths.sort();
+        paths
+    }
+
+    /// Removes the virtual file at `relative_path` so that it can be served
+    /// from `offset`. A virtual file can only be read once, so if `offset` is
+    /// past its end, it's left in place for a valid request.
+    fn take_virtual_file(
+        &self,
+        relative_path: &Path,
+        offset: u64,
+    ) -> Result<Option<VirtualFile>, ErrorCode> {
+        let mut virtual_files = self.virtual_files.lock().unwrap();
+
+        match virtual_files.get(relative_path) {
+            None => Ok(None),
+            Some(file) if offset > file.size() => Err(ErrorCode::InvalidRange),
+            Some(_) => Ok(virtual_files.remove(relative_path)),
+        }
+    }
+
+    /// Current totals in the Prometheus text format, for scraping.
+    pub fn metrics_text(&self) -> String {
+        self.metrics.render(self.sessions.len())
+    }
+
+    pub fn list_sessions(&self) -> Vec<SocketAddr> {
+        self.sessions.keys().copied().collect()
+    }
+
+    /// Number of sessions whose connection was lost and that can still be
+    /// resumed.
+    pub fn suspended_sessions(&self) -> usize {
+        let now = self.clock.now();
+        self.suspended
+            .values()
+            .filter(|session| session.expires_at > now)
+            .count()
+    }
+
+    /// Keeps `state` around until `expires_at`. If there are already `limit`
+    /// suspended sessions, the one that would expire first is dropped.
+    fn suspend(&mut self, state: SessionState, expires_at: Instant, limit: usize) {
+        if limit == 0 {
+            return;
+        }
+
+        let now = self.clock.now();
+        self.suspended.retain(|_, session| session.expires_at > now);
+
+        while self.suspended.len() >= limit {
+            let oldest = self
+                .suspended
+                .iter()
+                .min_by_key(|(_, session)| session.expires_at)
+                .map(|(id, _)| *id)
+                .unwrap();
+            self.suspended.remove(&oldest);
+        }
+
+        self.suspended
+            .insert(state.id, SuspendedSession { state, expires_at });
+    }
+
+    /// Removes and returns the suspended session `token` belongs to, if the
+    /// token is genuine and hasn't expired. Each token resumes a session at
+    /// most once per lost connection.
+    fn take_suspended(&mut self, token: &ResumptionToken, now: Instant) -> Option<SessionState> {
+        let (id, secret) = token.split()?;
+        let session = self.suspended.get(id)?;
+
+        if verify_slices_are_equal(&session.state.secret, secret).is_err() {
+            return None;
+        }
+
+        let session = self.suspended.remove(id)?;
+
+        if session.expires_at <= now {
+            return None;
+        }
+
+        Some(session.state)
+    }
+
+    /// Closes the connection to the client at `address` and forgets its
+    /// session. Returns false if there is no such session.
+    pub async fn disconnect(&mut self, address: SocketAddr) -> bool {
+        let session = match self.sessions.remove(&address) {
+            Some(session) => session,
+            None => return false,
+        };
+
+        let mut control = session.read().await.control.clone();
+
+        // Called with the server locked, so this mustn't wait for the session
+        // task. If the channel is full, a Close is already queued, and if it's
+        // closed, the session task has already stopped.
+        let _ = control.try_send(SessionControl::Close);
+
+        true
+    }
+
+    // The handshake runs in its own task so that a slow or stalling peer
+    // doesn't hold up the accept loop.
+    async fn accept_client(
+        server: Arc<RwLock<Server>>,
+        config: Arc<ServerConfig>,
+        server_channel: tokio::sync::mpsc::Sender<ControlMessage>,
+        stream: TcpStream,
+        address: SocketAddr,
+    ) {
+        let metrics = server.read().await.metrics.clone();
+
+        let connection =
+            match Connection::accept_encrypted(stream, &config.connection, &*server).await {
+                Ok(mut connection) => {
+                    connection.stream.count_traffic(metrics.traffic.clone());
+                    ServerMetrics::increment(&metrics.connections);
+                    ServerConnection::new(connection)
                 }
+                Err(error) => {
+                    println!("Handshake with {} failed: {}", address, error);
+                    ServerMetrics::increment(&metrics.handshake_failures);
+                    return;
+                }
+            };
+
+        let (control, session_control) = tokio::sync::mpsc::channel(1);
+        let session = Arc::new(RwLock::new(Session { address, control }));
+
+        let mut server_writer = server.write().await;
+        server_writer.sessions.insert(address, session.clone());
+        let context = SessionContext {
+            server: server.clone(),
+            fs: server_writer.fs.clone(),
+            clock: server_writer.clock.clone(),
+            metrics,
+            idle_timeout: config.get_idle_timeout(),
+            resumption_ttl: config.get_resumption_ttl(),
+            max_suspended_sessions: config.get_max_suspended_sessions(),
+        };
+        drop(server_writer);
+
+        Self::handle_client(
+            server_channel,
+            connection,
+            session,
+            session_control,
+            context,
+        )
+        .await;
+    }
+
+    async fn handle_control_message(server: &RwLock<Server>, message: ControlMessage) {
+        match message {
+            ControlMessage::Shutdown => {
+                todo!()
+            }
+            ControlMessage::Disconnect(address) => {
+                server.write().await.sessions.remove(&address);
             }
         }
     }
 
-    pub fn start_new(fs: Box<dyn FileSystem>, mut socket: TcpListener) -> Arc<RwLock<Server>> {
+    pub fn start_new(
+        fs: Box<dyn FileSystem>,
+        socket: TcpListener,
+        config: ServerConfig,
+    ) -> Arc<RwLock<Server>> {
+        Self::start_with_clock(fs, socket, config, Arc::new(TokioClock))
+    }
+
+    /// Like `start_new`, but with a custom clock for session timeouts and
+    /// accept backoff, and any `Listener`.
+    pub fn start_with_clock(
+        fs: Box<dyn FileSystem>,
+        mut socket: impl Listener,
+        mut config: ServerConfig,
+        clock: Arc<dyn Clock>,
+    ) -> Arc<RwLock<Server>> {
+        config.connection.clock.get_or_insert_with(|| clock.clone());
+
         let server = Server {
-            fs,
+            fs: Arc::from(fs),
+            clock: clock.clone(),
             sessions: HashMap::new(),
+            suspended: HashMap::new(),
+            metrics: Arc::new(ServerMetrics::default()),
+            virtual_files: Mutex::new(HashMap::new()),
         };
 
+        let config = Arc::new(config);
         let (sender, mut receiver) = tokio::sync::mpsc::channel(4);
 
         let server = Arc::new(RwLock::new(server));
         let closure_server = server.clone();
 
         task::spawn(async move {
+            let mut backoff = AcceptBackoff::new();
+
             loop {
                 let sender = sender.clone();
 
                 select! {
-                    Ok((stream, address)) = socket.accept() => {
-                        println!("Connection received from {}", address);
-                        let connection = Connection::new_encrypted(stream).await;
-                        let connection = ServerConnection::new(connection);
-
-                        let session = Arc::new(RwLock::new(Session { address }));
+                    result = socket.accept() => match result {
+                        Ok((stream, address)) => {
+                            backoff.on_success();
 
-                        let mut server_writer = closure_server.write().await;
-                        server_writer.sessions.insert(address, session.clone());
-                        drop(server_writer);
+                            println!("Connection received from {}", address);
 
-                        task::spawn(async move {
-                            Self::handle_client(sender, connection, session).await;
-                        });
+                            task::spawn(Self::accept_client(
+                                closure_server.clone(),
+                                config.clone(),
+                                sender,
+                                stream,
+                                address,
+                            ));
+                        }
+                        Err(error) => match backoff.on_error(&error) {
+                            Some(delay) => {
+                                println!(
+                                    "Failed to accept connection: {}. Retrying in {}ms.",
+                                    error,
+                                    delay.as_millis()
+                                );
+                                clock.sleep(delay).await;
+                            }
+                            None => {
+                                println!(
+                                    "Failed to accept connection: {}. Stopping server.",
+                                    error
+                                );
+                                break;
+                            }
+                        }
                     },
                     Some(control_message) = receiver.recv() => {
-                        match control_message {
-                            ControlMessage::Shutdown => {
-                                todo!()
-                            },
-                            ControlMessage::Disconnect(address) => { }
-                        }
+                        Self::handle_control_message(&closure_server, control_message).await;
                     }
                 }
             }
+
+            // Sessions that are still running report to this task when they
+            // end, so keep serving them until the last one is gone.
+            drop(sender);
+
+            while let Some(control_message) = receiver.recv().await {
+                Self::handle_control_message(&closure_server, control_message).await;
+            }
         });
 
         server
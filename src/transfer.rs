@@ -1,45 +1,433 @@
-use crate::config::ServerConfig;
+use crate::{
+    clock::{Clock, TokioClock},
+    config::ServerConfig,
+};
 use async_trait::async_trait;
 use crossbeam::queue::SegQueue;
-use futures::future;
+use futures::{future, stream, Future, Stream, StreamExt, TryStreamExt};
 use serde::{Deserialize, Serialize};
 use std::{
+    cmp,
+    collections::HashMap,
     path::{Path, PathBuf},
     sync::{
-        atomic::{AtomicU64, Ordering},
-        Arc,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
     },
-    time::SystemTime,
+    time::{Duration, Instant, SystemTime},
+};
+use thiserror::Error;
+use tokio::{
+    fs::read_dir,
+    io::{AsyncRead, AsyncReadExt},
 };
-use tokio::fs::read_dir;
+
+#[derive(Debug, Error)]
+pub enum DiscoveryError {
+    #[error("failed to read {path:?}: {source}")]
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("the receiver of discovered files was dropped")]
+    ChannelClosed,
+    #[error("discovery was cancelled")]
+    Cancelled,
+}
+
+#[derive(Debug, Error)]
+pub enum DiscoveryCacheError {
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("bincode error: {0}")]
+    Bincode(#[from] bincode::Error),
+}
+
+impl DiscoveryError {
+    /// Returns a function that attaches `path` to an IO error, for use with
+    /// `map_err`.
+    pub fn io(path: &Path) -> impl FnOnce(std::io::Error) -> Self + '_ {
+        move |source| DiscoveryError::Io {
+            path: path.to_owned(),
+            source,
+        }
+    }
+}
 
 #[derive(Debug)]
 pub enum DiscoveryMessage {
     Files(Vec<FileMetadata>),
 }
 
+/// Flattens the batches sent by `discover_files_recursively` into a stream of
+/// individual files. The stream ends once every sender has been dropped.
+pub fn discovery_stream(
+    receiver: tokio::sync::mpsc::Receiver<DiscoveryMessage>,
+) -> impl Stream<Item = FileMetadata> {
+    stream::unfold(receiver, |mut receiver| async move {
+        receiver.recv().await.map(|message| (message, receiver))
+    })
+    .flat_map(|message| match message {
+        DiscoveryMessage::Files(files) => stream::iter(files),
+    })
+}
+
+/// A `DiscoveryMessage` from one of the roots walked by `discover_roots`.
+#[derive(Debug)]
+pub struct RootedDiscoveryMessage {
+    /// The root the message's paths are relative to.
+    pub root: PathBuf,
+    pub message: DiscoveryMessage,
+}
+
+/// Walks every `(filesystem, root)` pair concurrently and sends what they find
+/// to `output`, tagged with the root. Returns once every walk has completed,
+/// so `output` is closed when the last of them finishes. If any of the walks
+/// fail, the others still run to completion and the first error is returned.
+pub async fn discover_roots<F>(
+    roots: Vec<(Arc<F>, PathBuf)>,
+    output: tokio::sync::mpsc::Sender<RootedDiscoveryMessage>,
+) -> Result<(), DiscoveryError>
+where
+    F: FileSystem + 'static,
+{
+    let walks = roots.into_iter().map(|(fs, root)| {
+        let mut output = output.clone();
+
+        async move {
+            let (sender, mut receiver) = tokio::sync::mpsc::channel(16);
+            let walk = fs.discover_files_recursively(root.clone(), sender);
+
+            let forward = async {
+                while let Some(message) = receiver.recv().await {
+                    let message = RootedDiscoveryMessage {
+                        root: root.clone(),
+                        message,
+                    };
+
+                    output
+                        .send(message)
+                        .await
+                        .map_err(|_| DiscoveryError::ChannelClosed)?;
+                }
+
+                Ok(())
+            };
+
+            // If forwarding fails, the receiver is dropped and the walk fails
+            // with the same error soon after.
+            let (walked, forwarded) = future::join(walk, forward).await;
+            walked.and(forwarded)
+        }
+    });
+
+    future::join_all(walks).await.into_iter().collect()
+}
+
+/// The contents of a single directory, as returned by
+/// `FileSystem::read_directory`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DirectoryListing {
+    pub files: Vec<FileMetadata>,
+    pub subdirectories: Vec<PathBuf>,
+}
+
 #[async_trait]
-pub trait FileSystem {
+pub trait FileSystem: Send + Sync {
     type Metadata;
 
     fn root(&self) -> &std::path::Path;
     fn convert_metadata(&self, path: &std::path::Path, metadata: Self::Metadata) -> FileMetadata;
 
+    /// Number of directories read in parallel by `discover_files_recursively`.
+    fn concurrency(&self) -> u32;
+
+    /// Lists the files and subdirectories directly under `path`. An empty
+    /// directory other than the root is listed as a file of its own, so that
+    /// it can be recreated on the other end.
+    async fn read_directory(&self, path: &Path) -> Result<DirectoryListing, DiscoveryError>;
+
+    /// The modification time of the directory itself, if the platform
+    /// provides one.
+    async fn directory_modified_at(
+        &self,
+        path: &Path,
+    ) -> Result<Option<SystemTime>, DiscoveryError>;
+
+    /// The metadata of a single file, without reading its contents, so
+    /// `content_hash` is always `None`.
+    async fn file_metadata(&self, path: &Path) -> Result<FileMetadata, DiscoveryError>;
+
+    /// Walks the tree under `path`, sending the files of each directory to
+    /// `output` as soon as it has been read.
+    ///
+    /// Every clone of `output` is dropped before this returns, whether it
+    /// succeeds or not, so a consumer can simply read until `recv` returns
+    /// `None`. Discovering nothing still completes and closes the channel.
     async fn discover_files_recursively(
         self: Arc<Self>,
         path: PathBuf,
         output: tokio::sync::mpsc::Sender<DiscoveryMessage>,
-    ) -> Result<(), anyhow::Error>;
+    ) -> Result<(), DiscoveryError>
+    where
+        Self: 'static,
+    {
+        let progress = Arc::new(DiscoveryProgress::new());
+        self.discover_with_progress(path, output, progress).await
+    }
+
+    /// Like `discover_files_recursively`, but reports progress to `progress`
+    /// so that it can be observed while discovery is running.
+    async fn discover_with_progress(
+        self: Arc<Self>,
+        path: PathBuf,
+        output: tokio::sync::mpsc::Sender<DiscoveryMessage>,
+        progress: Arc<DiscoveryProgress>,
+    ) -> Result<(), DiscoveryError>
+    where
+        Self: 'static,
+    {
+        let processing_queue = Arc::new(SegQueue::new());
+        let folders_to_process = Arc::new(AtomicU64::new(1));
+        // Set when a worker fails. The directories it had taken are never
+        // completed, so the others would otherwise wait for them forever.
+        let failed = Arc::new(AtomicBool::new(false));
+
+        processing_queue.push(path);
+        progress.queue_depth.fetch_add(1, Ordering::SeqCst);
+
+        let mut tasks = Vec::new();
+
+        for _ in 0..self.concurrency() {
+            let fs = self.clone();
+            let queue = processing_queue.clone();
+            let mut output = output.clone();
+            let folders_to_process = folders_to_process.clone();
+            let failed = failed.clone();
+            let progress = progress.clone();
+
+            let task = tokio::spawn(async move {
+                let result: Result<(), DiscoveryError> = async {
+                    loop {
+                        if folders_to_process.load(Ordering::SeqCst) == 0
+                            || failed.load(Ordering::SeqCst)
+                        {
+                            return Ok(());
+                        }
+
+                        // Other workers may still be reading directories that
+                        // refill the queue.
+                        if queue.is_empty() {
+                            tokio::task::yield_now().await
+                        }
+
+                        let path: PathBuf = match queue.pop() {
+                            Ok(path) => path,
+                            Err(_) => continue,
+                        };
+
+                        progress.queue_depth.fetch_sub(1, Ordering::SeqCst);
+                        progress.active_workers.fetch_add(1, Ordering::SeqCst);
+                        let listing = fs.read_directory(&path).await;
+                        progress.active_workers.fetch_sub(1, Ordering::SeqCst);
+                        let listing = listing?;
+
+                        for subdirectory in listing.subdirectories {
+                            folders_to_process.fetch_add(1, Ordering::SeqCst);
+                            progress.queue_depth.fetch_add(1, Ordering::SeqCst);
+                            queue.push(subdirectory);
+                        }
+
+                        output
+                            .send(DiscoveryMessage::Files(listing.files))
+                            .await
+                            .map_err(|_| DiscoveryError::ChannelClosed)?;
+
+                        progress.complete_directory();
+                        folders_to_process.fetch_sub(1, Ordering::SeqCst);
+                    }
+                }
+                .await;
+
+                if result.is_err() {
+                    failed.store(true, Ordering::SeqCst);
+                }
+
+                drop(output);
+                result
+            });
+
+            tasks.push(task);
+        }
+
+        // The workers hold the only remaining senders, so the channel closes
+        // as soon as the last of them stops.
+        drop(output);
+
+        let mut first_error = None;
+
+        for result in future::join_all(tasks).await {
+            let error = match result {
+                Ok(Ok(())) => continue,
+                Ok(Err(error)) => error,
+                Err(error) if error.is_panic() => std::panic::resume_unwind(error.into_panic()),
+                Err(_) => DiscoveryError::Cancelled,
+            };
+
+            first_error.get_or_insert(error);
+        }
+
+        progress.finished.store(true, Ordering::SeqCst);
+
+        match first_error {
+            Some(error) => Err(error),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Counters updated by the discovery workers. Cheap enough to update on every
+/// directory, and can be read from another task with `snapshot`.
+pub struct DiscoveryProgress {
+    clock: Arc<dyn Clock>,
+    started_at: Instant,
+    queue_depth: AtomicU64,
+    active_workers: AtomicU64,
+    completed_directories: AtomicU64,
+    // Milliseconds since `started_at`.
+    last_completion_ms: AtomicU64,
+    finished: AtomicBool,
+}
+
+/// A point-in-time view of `DiscoveryProgress`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DiscoverySnapshot {
+    /// Directories waiting to be read.
+    pub queue_depth: u64,
+    /// Workers currently reading a directory.
+    pub active_workers: u64,
+    pub completed_directories: u64,
+    /// Time since a directory was last completed, or since discovery started
+    /// if none has been.
+    pub since_last_completion: Duration,
+    pub finished: bool,
+}
+
+impl DiscoverySnapshot {
+    /// Whether there's work left but nothing has completed within `interval`.
+    pub fn is_stalled(&self, interval: Duration) -> bool {
+        let has_work = self.queue_depth > 0 || self.active_workers > 0;
+        !self.finished && has_work && self.since_last_completion >= interval
+    }
+}
+
+impl Default for DiscoveryProgress {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DiscoveryProgress {
+    pub fn new() -> Self {
+        Self::with_clock(Arc::new(TokioClock))
+    }
+
+    pub fn with_clock(clock: Arc<dyn Clock>) -> Self {
+        DiscoveryProgress {
+            started_at: clock.now(),
+            clock,
+            queue_depth: AtomicU64::new(0),
+            active_workers: AtomicU64::new(0),
+            completed_directories: AtomicU64::new(0),
+            last_completion_ms: AtomicU64::new(0),
+            finished: AtomicBool::new(false),
+        }
+    }
+
+    fn complete_directory(&self) {
+        let elapsed = (self.clock.now() - self.started_at).as_millis() as u64;
+        self.last_completion_ms.store(elapsed, Ordering::SeqCst);
+        self.completed_directories.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub fn snapshot(&self) -> DiscoverySnapshot {
+        let last_completion = Duration::from_millis(self.last_completion_ms.load(Ordering::SeqCst));
+
+        DiscoverySnapshot {
+            queue_depth: self.queue_depth.load(Ordering::SeqCst),
+            active_workers: self.active_workers.load(Ordering::SeqCst),
+            completed_directories: self.completed_directories.load(Ordering::SeqCst),
+            since_last_completion: self.clock.now() - self.started_at - last_completion,
+            finished: self.finished.load(Ordering::SeqCst),
+        }
+    }
+}
+
+/// Polls `progress` until discovery finishes, calling `warn` once for every
+/// period in which directories are outstanding but none has completed for
+/// `interval`. Useful for spotting a mount that hangs in the middle of a walk.
+pub async fn monitor_stalls<F>(progress: Arc<DiscoveryProgress>, interval: Duration, mut warn: F)
+where
+    F: FnMut(DiscoverySnapshot),
+{
+    let poll_interval = (interval / 4).max(Duration::from_millis(1));
+    let mut warned_at_completed = None;
+
+    loop {
+        progress.clock.sleep(poll_interval).await;
+
+        let snapshot = progress.snapshot();
+
+        if snapshot.finished {
+            break;
+        }
+
+        if snapshot.is_stalled(interval)
+            && warned_at_completed != Some(snapshot.completed_directories)
+        {
+            warned_at_completed = Some(snapshot.completed_directories);
+            warn(snapshot);
+        }
+    }
+}
+
+const HASH_BUFFER_SIZE: usize = 64 * 1024;
+
+pub(crate) async fn hash_file(path: &Path) -> Result<[u8; 32], std::io::Error> {
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut hasher = blake3::Hasher::new();
+    let mut buffer = vec![0u8; HASH_BUFFER_SIZE];
+
+    loop {
+        let read = file.read(&mut buffer).await?;
+
+        if read == 0 {
+            break;
+        }
+
+        hasher.update(&buffer[..read]);
+    }
+
+    Ok(*hasher.finalize().as_bytes())
 }
 
 pub struct StdFilesystem {
     root: std::path::PathBuf,
+    hash_contents: bool,
+    concurrency: u32,
 }
 
 impl StdFilesystem {
     pub fn new(root: impl AsRef<std::path::Path>) -> Self {
-        let root = root.as_ref().to_owned();
-        StdFilesystem { root }
+        Self::from_config(root, &ServerConfig::default())
+    }
+
+    pub fn from_config(root: impl AsRef<std::path::Path>, config: &ServerConfig) -> Self {
+        StdFilesystem {
+            root: root.as_ref().to_owned(),
+            hash_contents: config.get_hash_during_discovery(),
+            concurrency: config.get_discovery_concurrency(),
+        }
     }
 }
 
@@ -52,131 +440,570 @@ impl FileSystem for StdFilesystem {
     }
 
     fn convert_metadata(&self, path: &std::path::Path, metadata: Self::Metadata) -> FileMetadata {
+        let is_directory = metadata.is_dir();
+
         FileMetadata {
             relative_path: path.strip_prefix(&self.root).unwrap().to_owned(),
             created_at: metadata.created().ok(),
             modified_at: metadata.modified().ok(),
-            uncompressed_size: metadata.len(),
+            uncompressed_size: if is_directory { 0 } else { metadata.len() },
+            is_directory,
+            compression: Compression::Stored,
+            content_hash: None,
         }
     }
 
-    async fn discover_files_recursively(
-        self: Arc<Self>,
-        path: PathBuf,
-        output: tokio::sync::mpsc::Sender<DiscoveryMessage>,
-    ) -> Result<(), anyhow::Error> {
-        let processing_queue = Arc::new(SegQueue::new());
-        let folders_to_process = Arc::new(AtomicU64::new(1));
+    fn concurrency(&self) -> u32 {
+        self.concurrency
+    }
 
-        processing_queue.push(path);
+    async fn read_directory(&self, path: &Path) -> Result<DirectoryListing, DiscoveryError> {
+        let mut file_stream = read_dir(path).await.map_err(DiscoveryError::io(path))?;
+        let mut listing = DirectoryListing::default();
+        let mut is_empty = true;
+
+        while let Some(entry) = file_stream
+            .next_entry()
+            .await
+            .map_err(DiscoveryError::io(path))?
+        {
+            is_empty = false;
+
+            let path = entry.path();
+            let file_type = entry.file_type().await.map_err(DiscoveryError::io(&path))?;
+
+            if file_type.is_dir() {
+                listing.subdirectories.push(path);
+            } else {
+                let metadata = entry.metadata().await.map_err(DiscoveryError::io(&path))?;
+                let mut metadata = self.convert_metadata(&path, metadata);
+
+                // Each worker hashes one file at a time, so the concurrency
+                // limit also bounds open files.
+                if self.hash_contents {
+                    let hash = hash_file(&path).await.map_err(DiscoveryError::io(&path))?;
+                    metadata.content_hash = Some(hash);
+                }
 
-        let mut tasks = Vec::new();
+                listing.files.push(metadata);
+            }
+        }
 
-        const CONCURRENCY_LIMIT: u32 = 16;
+        if is_empty && path != self.root {
+            let metadata = tokio::fs::metadata(path)
+                .await
+                .map_err(DiscoveryError::io(path))?;
+            listing.files.push(self.convert_metadata(path, metadata));
+        }
 
-        for _ in 0..CONCURRENCY_LIMIT {
-            let fs = self.clone();
-            let queue = processing_queue.clone();
-            let mut output = output.clone();
-            let folders_to_process = folders_to_process.clone();
+        Ok(listing)
+    }
 
-            let task = tokio::spawn(async move {
-                loop {
-                    if folders_to_process.load(Ordering::SeqCst) == 0 {
-                        break;
-                    }
+    async fn directory_modified_at(
+        &self,
+        path: &Path,
+    ) -> Result<Option<SystemTime>, DiscoveryError> {
+        let metadata = tokio::fs::metadata(path)
+            .await
+            .map_err(DiscoveryError::io(path))?;
+        Ok(metadata.modified().ok())
+    }
 
-                    let path: PathBuf = match queue.pop() {
-                        Ok(path) => path,
-                        Err(_) => {
-                            tokio::task::yield_now().await;
-                            continue;
-                        }
-                    };
+    async fn file_metadata(&self, path: &Path) -> Result<FileMetadata, DiscoveryError> {
+        // Like the listing, which doesn't follow symlinks either.
+        let metadata = tokio::fs::symlink_metadata(path)
+            .await
+            .map_err(DiscoveryError::io(path))?;
+        Ok(self.convert_metadata(path, metadata))
+    }
+}
 
-                    let mut file_stream = read_dir(path).await?;
-                    let mut files = Vec::new();
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CachedDirectory {
+    modified_at: SystemTime,
+    listing: DirectoryListing,
+}
 
-                    while let Some(entry) = file_stream.next_entry().await? {
-                        let file_type = entry.file_type().await?;
-                        let path = entry.path();
+/// Directory listings from a previous discovery run, keyed by directory path.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct DiscoveryCache {
+    directories: HashMap<PathBuf, CachedDirectory>,
+}
 
-                        if file_type.is_dir() {
-                            folders_to_process.fetch_add(1, Ordering::SeqCst);
-                            queue.push(path);
-                        } else {
-                            let metadata = entry.metadata().await?;
-                            let metadata = fs.convert_metadata(&path, metadata);
-                            files.push(metadata);
-                        }
-                    }
+impl DiscoveryCache {
+    /// Loads a cache saved by `save`. A missing file is an empty cache.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, DiscoveryCacheError> {
+        match std::fs::read(path) {
+            Ok(bytes) => Ok(bincode::deserialize(&bytes)?),
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(error) => Err(error.into()),
+        }
+    }
 
-                    output.send(DiscoveryMessage::Files(files)).await?;
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), DiscoveryCacheError> {
+        let bytes = bincode::serialize(self)?;
+        std::fs::write(path, bytes)?;
+        Ok(())
+    }
 
-                    folders_to_process.fetch_sub(1, Ordering::SeqCst);
-                }
+    pub fn len(&self) -> usize {
+        self.directories.len()
+    }
 
-                let ret: Result<(), anyhow::Error> = Ok(());
-                ret
-            });
+    pub fn is_empty(&self) -> bool {
+        self.directories.is_empty()
+    }
+}
 
-            tasks.push(task);
+/// Wraps a `FileSystem` so that directories whose modification time hasn't
+/// changed since the previous run are listed from the cache instead of being
+/// read again.
+///
+/// A directory's modification time only changes when entries are added,
+/// removed or renamed, not when a file in it is modified in place. So every
+/// cached file is checked with `file_metadata`, and the directory is read
+/// again if any of their sizes or modification times have changed.
+pub struct CachingFileSystem<F> {
+    inner: F,
+    previous: DiscoveryCache,
+    current: Mutex<DiscoveryCache>,
+}
+
+impl<F: FileSystem> CachingFileSystem<F> {
+    pub fn new(inner: F, previous: DiscoveryCache) -> Self {
+        CachingFileSystem {
+            inner,
+            previous,
+            current: Mutex::new(DiscoveryCache::default()),
         }
+    }
 
-        future::join_all(tasks).await;
+    /// The directories seen during this run. Directories that no longer exist
+    /// are left out, so saving this keeps the cache from growing forever.
+    pub fn cache(&self) -> DiscoveryCache {
+        let current = self.current.lock().unwrap();
 
-        Ok(())
+        DiscoveryCache {
+            directories: current.directories.clone(),
+        }
     }
+
+    pub fn into_inner(self) -> F {
+        self.inner
+    }
+
+    async fn files_are_unchanged(
+        &self,
+        listing: &DirectoryListing,
+    ) -> Result<bool, DiscoveryError> {
+        for file in &listing.files {
+            // An empty directory is only listed while its own modification time
+            // is unchanged.
+            if file.is_directory {
+                continue;
+            }
+
+            let path = self.inner.root().join(&file.relative_path);
+
+            let current = match self.inner.file_metadata(&path).await {
+                Ok(current) => current,
+                Err(DiscoveryError::Io { source, .. })
+                    if source.kind() == std::io::ErrorKind::NotFound =>
+                {
+                    return Ok(false)
+                }
+                Err(error) => return Err(error),
+            };
+
+            if current.uncompressed_size != file.uncompressed_size
+                || current.modified_at != file.modified_at
+            {
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+    }
+}
+
+#[async_trait]
+impl<F: FileSystem> FileSystem for CachingFileSystem<F> {
+    type Metadata = F::Metadata;
+
+    fn root(&self) -> &Path {
+        self.inner.root()
+    }
+
+    fn convert_metadata(&self, path: &Path, metadata: Self::Metadata) -> FileMetadata {
+        self.inner.convert_metadata(path, metadata)
+    }
+
+    fn concurrency(&self) -> u32 {
+        self.inner.concurrency()
+    }
+
+    async fn read_directory(&self, path: &Path) -> Result<DirectoryListing, DiscoveryError> {
+        let modified_at = match self.inner.directory_modified_at(path).await? {
+            Some(modified_at) => modified_at,
+            // Without a modification time there's no way to tell whether the
+            // cached listing is still valid.
+            None => return self.inner.read_directory(path).await,
+        };
+
+        let cached = match self.previous.directories.get(path) {
+            Some(cached) if cached.modified_at == modified_at => Some(&cached.listing),
+            _ => None,
+        };
+
+        let listing = match cached {
+            Some(cached) if self.files_are_unchanged(cached).await? => cached.clone(),
+            _ => self.inner.read_directory(path).await?,
+        };
+
+        self.current.lock().unwrap().directories.insert(
+            path.to_owned(),
+            CachedDirectory {
+                modified_at,
+                listing: listing.clone(),
+            },
+        );
+
+        Ok(listing)
+    }
+
+    async fn directory_modified_at(
+        &self,
+        path: &Path,
+    ) -> Result<Option<SystemTime>, DiscoveryError> {
+        self.inner.directory_modified_at(path).await
+    }
+
+    async fn file_metadata(&self, path: &Path) -> Result<FileMetadata, DiscoveryError> {
+        self.inner.file_metadata(path).await
+    }
+}
+
+/// A group of small files that are sent together as a single unit.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Batch {
+    pub files: Vec<FileMetadata>,
+    pub total_size: u64,
 }
 
-struct Batch {}
+#[derive(Debug, PartialEq, Eq)]
+pub struct TransferPlan {
+    /// Empty directories. Other directories are implied by the files in them.
+    pub directories: Vec<FileMetadata>,
+    pub bundles: Vec<Batch>,
+    pub single_chunk_files: Vec<FileMetadata>,
+    pub large_files: Vec<FileMetadata>,
+}
 
-pub struct TransferPlan {}
+/// A unit of work in a transfer: either a bundle of small files or a single
+/// file that is sent on its own.
+#[derive(Debug, Clone, Copy)]
+pub enum TransferItem<'a> {
+    Bundle(&'a Batch),
+    File(&'a FileMetadata),
+}
 
 impl TransferPlan {
-    pub fn create(mut files: Vec<FileMetadata>, config: &ServerConfig) -> Self {
-        files.sort_unstable_by_key(|k| k.uncompressed_size);
+    pub fn items(&self) -> impl Iterator<Item = TransferItem<'_>> {
+        let bundles = self.bundles.iter().map(TransferItem::Bundle);
+        let files = self
+            .single_chunk_files
+            .iter()
+            .chain(self.large_files.iter())
+            .map(TransferItem::File);
+
+        bundles.chain(files)
+    }
+
+    /// Calls `send` for every bundle and file in the plan, keeping at most
+    /// `concurrency` of them in flight. Stops at the first error.
+    pub async fn execute<'a, F, Fut, E>(&'a self, concurrency: usize, send: F) -> Result<(), E>
+    where
+        F: FnMut(TransferItem<'a>) -> Fut,
+        Fut: Future<Output = Result<(), E>>,
+    {
+        stream::iter(self.items().map(Ok))
+            .try_for_each_concurrent(concurrency, send)
+            .await
+    }
+
+    pub fn create(files: Vec<FileMetadata>, config: &ServerConfig) -> Self {
+        let (directories, mut files): (Vec<_>, Vec<_>) =
+            files.into_iter().partition(|file| file.is_directory);
+
+        // Discovery returns files in whatever order the workers happen to
+        // finish, so ties are broken by path to keep plans reproducible.
+        files.sort();
 
         let small_file_threshold = config.get_small_file_threshold();
         let first_non_small_file_index = files
             .iter()
-            .enumerate()
-            .skip_while(|(_, file)| file.uncompressed_size < small_file_threshold)
-            .next()
-            .map(|(i, _)| i)
-            .unwrap();
-
-        let small_files = &files[0..first_non_small_file_index];
+            .position(|file| file.uncompressed_size >= small_file_threshold)
+            .unwrap_or(files.len());
 
         let large_file_threshold = config.get_large_file_threshold();
-        let first_large_file_index = files
+        let first_large_file_index = files[first_non_small_file_index..]
             .iter()
-            .enumerate()
-            .skip(first_non_small_file_index)
-            .skip_while(|(_, file)| file.uncompressed_size < large_file_threshold)
-            .next()
-            .map(|(i, _)| i)
-            .unwrap();
-
-        let single_chunk_files = &files[first_non_small_file_index..first_large_file_index];
-
-        let large_files = &files[first_large_file_index..];
-
-        println!(
-            "Small files: {}\nSingle chunk files: {}\nLarge files: {}",
-            small_files.len(),
-            single_chunk_files.len(),
-            large_files.len()
-        );
+            .position(|file| file.uncompressed_size >= large_file_threshold)
+            .map(|i| i + first_non_small_file_index)
+            .unwrap_or(files.len());
+
+        let large_files = files.split_off(first_large_file_index);
+        let single_chunk_files = files.split_off(first_non_small_file_index);
+        let small_files = files;
+
+        let bundles = Self::bundle_small_files(small_files, config.get_bundle_target_size());
+
+        TransferPlan {
+            directories,
+            bundles,
+            single_chunk_files,
+            large_files,
+        }
+    }
+
+    fn bundle_small_files(files: Vec<FileMetadata>, target_size: u64) -> Vec<Batch> {
+        let mut bundles = Vec::new();
+        let mut bundle = Batch::default();
+
+        for file in files {
+            bundle.total_size += file.uncompressed_size;
+            bundle.files.push(file);
+
+            if bundle.total_size >= target_size {
+                bundles.push(std::mem::take(&mut bundle));
+            }
+        }
+
+        // Zero-byte files never fill a bundle, so they always end up here at
+        // the latest.
+        if !bundle.files.is_empty() {
+            bundles.push(bundle);
+        }
 
-        todo!();
+        bundles
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct FileMetadata {
+/// A file whose contents come from an `AsyncRead` rather than the disk, e.g. an
+/// archive that is assembled while it's being sent.
+///
+/// The reader is consumed by the transfer, so a virtual file can be sent only
+/// once. It must produce exactly `size` bytes; anything past that is ignored,
+/// and ending early fails the transfer.
+pub struct VirtualFile {
     relative_path: PathBuf,
-    created_at: Option<SystemTime>,
-    modified_at: Option<SystemTime>,
-    uncompressed_size: u64,
+    size: u64,
+    reader: Box<dyn AsyncRead + Send + Sync + Unpin>,
+}
+
+impl VirtualFile {
+    pub fn new(
+        relative_path: impl Into<PathBuf>,
+        size: u64,
+        reader: impl AsyncRead + Send + Sync + Unpin + 'static,
+    ) -> Self {
+        VirtualFile {
+            relative_path: relative_path.into(),
+            size,
+            reader: Box::new(reader),
+        }
+    }
+
+    pub fn relative_path(&self) -> &Path {
+        &self.relative_path
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Metadata for planning, as if the file had been discovered on disk.
+    pub fn metadata(&self) -> FileMetadata {
+        FileMetadata {
+            relative_path: self.relative_path.clone(),
+            created_at: None,
+            modified_at: None,
+            uncompressed_size: self.size,
+            is_directory: false,
+            compression: Compression::Stored,
+            content_hash: None,
+        }
+    }
+
+    pub(crate) fn into_reader(self) -> Box<dyn AsyncRead + Send + Sync + Unpin> {
+        self.reader
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct FileMetadata {
+    pub relative_path: PathBuf,
+    pub created_at: Option<SystemTime>,
+    pub modified_at: Option<SystemTime>,
+    pub uncompressed_size: u64,
+    /// Set for empty directories, which are reported so that they can be
+    /// recreated on the receiving side.
+    pub is_directory: bool,
+    pub compression: Compression,
+    /// BLAKE3 hash of the file's contents, if hashing was enabled.
+    pub content_hash: Option<[u8; 32]>,
+}
+
+/// How a file's contents are sent. There's no codec to compress them with yet,
+/// so every file is sent as it is stored on disk.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Compression {
+    Stored,
+}
+
+impl FileMetadata {
+    /// The creation time to use for ordering and conflict resolution.
+    ///
+    /// Many Linux filesystems don't record when a file was created, so
+    /// `created_at` is often `None`.
+    /// In that case the modification time is used instead, since a file
+    /// can't have been modified before it was created. Code that needs a
+    /// creation time should go through this rather than `created_at`.
+    pub fn effective_created_at(&self) -> Option<SystemTime> {
+        self.created_at.or(self.modified_at)
+    }
+
+    /// Orders files by size, then by path. Every file in a transfer has a
+    /// distinct path, so this is a total order over them. `Ord` compares
+    /// this first.
+    pub fn sort_key(&self) -> (u64, &Path) {
+        (self.uncompressed_size, &self.relative_path)
+    }
+
+    /// Whether `other` appears to be the same version of this file, judging by
+    /// size and modification time.
+    pub fn is_unchanged(&self, other: &FileMetadata, mtime_tolerance: Duration) -> bool {
+        self.uncompressed_size == other.uncompressed_size
+            && timestamps_match(self.modified_at, other.modified_at, mtime_tolerance)
+    }
+}
+
+impl Ord for FileMetadata {
+    fn cmp(&self, other: &Self) -> cmp::Ordering {
+        // The rest of the fields only break ties between different versions
+        // of the same file, which keeps this consistent with `Eq`.
+        let rest = |file: &Self| {
+            (
+                file.created_at,
+                file.modified_at,
+                file.is_directory,
+                file.compression,
+                file.content_hash,
+            )
+        };
+
+        self.sort_key()
+            .cmp(&other.sort_key())
+            .then_with(|| rest(self).cmp(&rest(other)))
+    }
+}
+
+impl PartialOrd for FileMetadata {
+    fn partial_cmp(&self, other: &Self) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Compares two timestamps, treating them as equal if they are within
+/// `tolerance` of each other.
+///
+/// Filesystems store modification times with different precision: ext4 and
+/// NTFS keep sub-second timestamps while FAT rounds them to two seconds. A file
+/// copied between them gets a slightly different mtime, and comparing exactly
+/// would make every such file look modified.
+pub fn timestamps_match(a: Option<SystemTime>, b: Option<SystemTime>, tolerance: Duration) -> bool {
+    match (a, b) {
+        (Some(a), Some(b)) => {
+            let difference = a.duration_since(b).unwrap_or_else(|error| error.duration());
+            difference <= tolerance
+        }
+        (a, b) => a == b,
+    }
+}
+
+/// Files whose size is at least `min` and, unless this is the last bucket,
+/// less than `max`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SizeBucket {
+    pub min: u64,
+    pub max: Option<u64>,
+    pub count: u64,
+    pub total_bytes: u64,
+}
+
+/// The distribution of file sizes in a tree, for choosing thresholds.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Histogram {
+    pub buckets: Vec<SizeBucket>,
+}
+
+/// Counts the files in each size bucket. `bounds` are the sizes at which a
+/// new bucket starts, in any order. The buckets below the first bound and at
+/// or above the last one are always included, even if empty. Directories are
+/// not counted.
+pub fn size_histogram(files: &[FileMetadata], bounds: &[u64]) -> Histogram {
+    let mut bounds = bounds.to_vec();
+    bounds.sort_unstable();
+    bounds.dedup();
+
+    let mins = std::iter::once(0).chain(bounds.iter().copied());
+    let maxes = bounds
+        .iter()
+        .copied()
+        .map(Some)
+        .chain(std::iter::once(None));
+
+    let mut buckets: Vec<SizeBucket> = mins
+        .zip(maxes)
+        .filter(|&(min, max)| max != Some(min))
+        .map(|(min, max)| SizeBucket {
+            min,
+            max,
+            count: 0,
+            total_bytes: 0,
+        })
+        .collect();
+
+    for file in files.iter().filter(|file| !file.is_directory) {
+        let size = file.uncompressed_size;
+        let index = buckets.partition_point(|bucket| bucket.min <= size) - 1;
+
+        buckets[index].count += 1;
+        buckets[index].total_bytes += size;
+    }
+
+    Histogram { buckets }
+}
+
+/// Returns the files in `source` that are missing from or differ in
+/// `destination`.
+pub fn diff<'a>(
+    source: &'a [FileMetadata],
+    destination: &[FileMetadata],
+    mtime_tolerance: Duration,
+) -> Vec<&'a FileMetadata> {
+    let existing_files: HashMap<&Path, &FileMetadata> = destination
+        .iter()
+        .map(|file| (file.relative_path.as_path(), file))
+        .collect();
+
+    source
+        .iter()
+        .filter(
+            |file| match existing_files.get(file.relative_path.as_path()) {
+                Some(existing) => !file.is_unchanged(existing, mtime_tolerance),
+                None => true,
+            },
+        )
+        .collect()
 }
@@ -0,0 +1,168 @@
+mod common;
+
+use async_trait::async_trait;
+use pneumatic::{
+    client::Client,
+    clock::MockClock,
+    config::{ConnectionConfig, ServerConfig},
+    protocol::ClientMessage,
+    server::{AcceptBackoff, Listener, MockFileSystem, Server},
+};
+use std::{
+    collections::VecDeque,
+    io,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    time::timeout,
+};
+
+#[test]
+fn repeated_accept_errors_back_off() {
+    let mut backoff = AcceptBackoff::new();
+
+    // EMFILE: too many open files.
+    let error = io::Error::from_raw_os_error(24);
+
+    let delays: Vec<Duration> = (0..20).map(|_| backoff.on_error(&error).unwrap()).collect();
+
+    assert!(delays[0] > Duration::from_millis(0));
+    assert!(delays.windows(2).all(|pair| pair[0] <= pair[1]));
+    assert!(delays[3] > delays[0]);
+    assert_eq!(*delays.last().unwrap(), Duration::from_secs(1));
+
+    backoff.on_success();
+    assert_eq!(backoff.on_error(&error), Some(delays[0]));
+}
+
+#[test]
+fn invalid_listener_is_fatal() {
+    let mut backoff = AcceptBackoff::new();
+    let error = io::Error::new(io::ErrorKind::InvalidInput, "not listening");
+
+    assert_eq!(backoff.on_error(&error), None);
+}
+
+#[tokio::test]
+async fn bind_uses_configured_backlog() {
+    let config = ServerConfig {
+        listen_backlog: Some(16),
+        ..ServerConfig::default()
+    };
+
+    let address: SocketAddr = "127.0.0.1:0".parse().unwrap();
+    let mut listener = Server::bind(address, &config).unwrap();
+    let address = listener.local_addr().unwrap();
+
+    let (client, server) = futures::join!(TcpStream::connect(address), listener.accept());
+    client.unwrap();
+    server.unwrap();
+}
+
+/// Fails with the scripted errors before accepting real connections from
+/// `inner`. A `None` in the script lets a single connection through.
+struct ScriptedListener {
+    inner: TcpListener,
+    script: VecDeque<Option<io::ErrorKind>>,
+    attempts: Arc<AtomicUsize>,
+}
+
+#[async_trait]
+impl Listener for ScriptedListener {
+    async fn accept(&mut self) -> io::Result<(TcpStream, SocketAddr)> {
+        self.attempts.fetch_add(1, Ordering::SeqCst);
+
+        match self.script.pop_front() {
+            Some(Some(kind)) => Err(io::Error::new(kind, "scripted accept error")),
+            Some(None) | None => self.inner.accept().await,
+        }
+    }
+}
+
+async fn wait_for_attempts(attempts: &AtomicUsize, count: usize) {
+    timeout(Duration::from_secs(5), async {
+        while attempts.load(Ordering::SeqCst) < count {
+            tokio::task::yield_now().await
+        }
+    })
+    .await
+    .expect("accept wasn't retried");
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn accept_loop_waits_before_retrying() {
+    let clock = Arc::new(MockClock::new());
+    let (inner, address) = common::bind_loopback().await;
+    let attempts = Arc::new(AtomicUsize::new(0));
+    let listener = ScriptedListener {
+        inner,
+        script: vec![Some(io::ErrorKind::Other); 2].into(),
+        attempts: attempts.clone(),
+    };
+
+    let _server = Server::start_with_clock(
+        Box::new(MockFileSystem::new()),
+        listener,
+        ServerConfig::default(),
+        clock.clone(),
+    );
+
+    // The first retry comes after 10ms, and the second after another 20ms.
+    for (failed, delay) in [(1, 10), (2, 20)].iter().copied() {
+        wait_for_attempts(&attempts, failed).await;
+
+        clock.advance(Duration::from_millis(delay - 1));
+        tokio::time::delay_for(Duration::from_millis(50)).await;
+        assert_eq!(attempts.load(Ordering::SeqCst), failed);
+
+        clock.advance(Duration::from_millis(1));
+        wait_for_attempts(&attempts, failed + 1).await;
+    }
+
+    // Out of errors, so connections go through again.
+    Client::connect(address, &ConnectionConfig::default())
+        .await
+        .unwrap();
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn sessions_are_cleaned_up_after_fatal_accept_error() {
+    let (inner, address) = common::bind_loopback().await;
+    let listener = ScriptedListener {
+        inner,
+        script: vec![None, Some(io::ErrorKind::InvalidInput)].into(),
+        attempts: Arc::new(AtomicUsize::new(0)),
+    };
+
+    let server = Server::start_with_clock(
+        Box::new(MockFileSystem::new()),
+        listener,
+        ServerConfig::default(),
+        Arc::new(MockClock::new()),
+    );
+
+    let mut client = Client::connect(address, &ConnectionConfig::default())
+        .await
+        .unwrap();
+    client.greet(None).await.unwrap();
+    assert_eq!(server.read().await.list_sessions().len(), 1);
+
+    client
+        .send_message(ClientMessage::Disconnect)
+        .await
+        .unwrap();
+
+    timeout(Duration::from_secs(5), async {
+        while !server.read().await.list_sessions().is_empty() {
+            tokio::task::yield_now().await
+        }
+    })
+    .await
+    .expect("session wasn't removed");
+}
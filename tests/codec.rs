@@ -0,0 +1,109 @@
+mod common;
+
+use pneumatic::{
+    codec::{BincodeCodec, Codec, Format},
+    config::ConnectionConfig,
+    crypto::EncryptedStream,
+    protocol::{CancelRequest, ClientMessage, CreditUpdate, FileRequest, Greeting},
+};
+use std::path::PathBuf;
+
+fn messages() -> Vec<ClientMessage> {
+    vec![
+        Greeting {
+            protocol_version: 1,
+            receive_window: Some(8),
+        }
+        .into(),
+        CreditUpdate { frames: 4 }.into(),
+        ClientMessage::ListFiles,
+        FileRequest {
+            request_id: 5,
+            relative_path: PathBuf::from("photos").join("beach.jpg"),
+        }
+        .into(),
+        CancelRequest { request_id: 5 }.into(),
+        ClientMessage::Disconnect,
+    ]
+}
+
+fn assert_round_trips(codec: impl Codec) {
+    for message in messages() {
+        let encoded = codec.encode(&message, 1024).unwrap();
+        let decoded: ClientMessage = codec.decode(&encoded).unwrap();
+        assert_eq!(decoded, message);
+    }
+}
+
+#[test]
+fn client_messages_round_trip_through_bincode() {
+    assert_round_trips(BincodeCodec);
+}
+
+#[cfg(feature = "msgpack")]
+#[test]
+fn client_messages_round_trip_through_messagepack() {
+    assert_round_trips(pneumatic::codec::MessagePackCodec);
+}
+
+async fn negotiated_formats(first: Option<Format>, second: Option<Format>) -> (Format, Format) {
+    let config = |format| ConnectionConfig {
+        format,
+        ..ConnectionConfig::default()
+    };
+
+    let (first_config, second_config) = (config(first), config(second));
+
+    let (first_stream, second_stream) = common::tcp_pair().await;
+    let (first_stream, second_stream) = futures::join!(
+        EncryptedStream::new(first_stream, &first_config),
+        EncryptedStream::new(second_stream, &second_config)
+    );
+
+    (
+        first_stream.unwrap().format(),
+        second_stream.unwrap().format(),
+    )
+}
+
+#[tokio::test]
+async fn bincode_is_negotiated_by_default() {
+    assert_eq!(
+        negotiated_formats(None, None).await,
+        (Format::Bincode, Format::Bincode)
+    );
+}
+
+#[cfg(feature = "msgpack")]
+#[tokio::test]
+async fn either_peer_can_ask_for_messagepack() {
+    let msgpack = Some(Format::MessagePack);
+
+    assert_eq!(
+        negotiated_formats(msgpack, None).await,
+        (Format::MessagePack, Format::MessagePack)
+    );
+    assert_eq!(
+        negotiated_formats(None, msgpack).await,
+        (Format::MessagePack, Format::MessagePack)
+    );
+}
+
+#[cfg(feature = "msgpack")]
+#[tokio::test]
+async fn messages_cross_a_messagepack_connection() {
+    let config = ConnectionConfig {
+        format: Some(Format::MessagePack),
+        ..ConnectionConfig::default()
+    };
+    let (mut client, mut server) = common::encrypted_pair(&config).await;
+
+    for message in messages() {
+        client.send(&message).await.unwrap();
+        client.flush().await.unwrap();
+
+        let mut buffer = Vec::new();
+        let received: ClientMessage = server.receive(&mut buffer).await.unwrap();
+        assert_eq!(received, message);
+    }
+}
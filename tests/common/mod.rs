@@ -0,0 +1,165 @@
+#![allow(dead_code)]
+
+use pneumatic::{clock::MockClock, config::ConnectionConfig, crypto::EncryptedStream};
+use std::{
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    path::PathBuf,
+    sync::atomic::{AtomicU32, Ordering},
+    time::Duration,
+};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    task::JoinHandle,
+};
+
+static TEMP_DIR_COUNTER: AtomicU32 = AtomicU32::new(0);
+
+/// Creates a fresh, empty directory under the system temp directory.
+pub fn temp_dir(name: &str) -> PathBuf {
+    let unique = TEMP_DIR_COUNTER.fetch_add(1, Ordering::SeqCst);
+    let path = std::env::temp_dir().join(format!(
+        "pneumatic-test-{}-{}-{}",
+        std::process::id(),
+        name,
+        unique
+    ));
+
+    if path.exists() {
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+
+    std::fs::create_dir_all(&path).unwrap();
+    path
+}
+
+/// Binds a listener on an OS-assigned loopback port.
+pub async fn bind_loopback() -> (TcpListener, SocketAddrV4) {
+    let listener = TcpListener::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0))
+        .await
+        .unwrap();
+
+    let address = match listener.local_addr().unwrap() {
+        SocketAddr::V4(address) => address,
+        SocketAddr::V6(_) => unreachable!(),
+    };
+
+    (listener, address)
+}
+
+/// Returns two raw TCP streams connected to each other over loopback.
+pub async fn tcp_pair() -> (TcpStream, TcpStream) {
+    let (mut listener, address) = bind_loopback().await;
+    let (client, server) = futures::join!(TcpStream::connect(address), listener.accept());
+    (client.unwrap(), server.unwrap().0)
+}
+
+/// Returns two encrypted streams that have completed the handshake with each
+/// other.
+pub async fn encrypted_pair(config: &ConnectionConfig) -> (EncryptedStream, EncryptedStream) {
+    let (client, server) = tcp_pair().await;
+    let (client, server) = futures::join!(
+        EncryptedStream::new(client, config),
+        EncryptedStream::new(server, config)
+    );
+    (client.unwrap(), server.unwrap())
+}
+
+/// Advances `clock` by `step` until `task` finishes. A single advance isn't
+/// enough, because the task may not have started waiting yet.
+pub async fn advance_until_done<T>(
+    clock: &MockClock,
+    step: Duration,
+    mut task: JoinHandle<T>,
+) -> T {
+    loop {
+        clock.advance(step);
+
+        if let Ok(output) = tokio::time::timeout(Duration::from_millis(10), &mut task).await {
+            return output.unwrap();
+        }
+    }
+}
+
+/// Runs discovery over `root` and collects every reported entry.
+pub async fn discover_all(root: &std::path::Path) -> Vec<pneumatic::transfer::FileMetadata> {
+    discover_all_with_config(root, &pneumatic::config::ServerConfig::default()).await
+}
+
+pub async fn discover_all_with_config(
+    root: &std::path::Path,
+    config: &pneumatic::config::ServerConfig,
+) -> Vec<pneumatic::transfer::FileMetadata> {
+    use futures::StreamExt;
+    use pneumatic::transfer::{discovery_stream, FileSystem, StdFilesystem};
+    use std::sync::Arc;
+
+    let fs = Arc::new(StdFilesystem::from_config(root, config));
+    let (sender, receiver) = tokio::sync::mpsc::channel(16);
+
+    let discover = tokio::spawn(fs.discover_files_recursively(root.to_owned(), sender));
+    let files = discovery_stream(receiver).collect().await;
+    discover.await.unwrap().unwrap();
+
+    files
+}
+
+/// Creates metadata for a regular file without touching the disk.
+pub fn file_metadata(path: &str, size: u64) -> pneumatic::transfer::FileMetadata {
+    pneumatic::transfer::FileMetadata {
+        relative_path: PathBuf::from(path),
+        created_at: None,
+        modified_at: None,
+        uncompressed_size: size,
+        is_directory: false,
+        compression: pneumatic::transfer::Compression::Stored,
+        content_hash: None,
+    }
+}
+
+/// Requests a file and collects its chunks until the server reports the end
+/// of the file or an error.
+pub async fn download(
+    client: &mut pneumatic::client::Client,
+    relative_path: &str,
+) -> Result<Vec<u8>, pneumatic::protocol::ErrorCode> {
+    client.request_file(relative_path).await.unwrap();
+    receive_file(client).await
+}
+
+/// Collects the chunks of a requested file until the server reports the end
+/// of the file or an error.
+pub async fn receive_file(
+    client: &mut pneumatic::client::Client,
+) -> Result<Vec<u8>, pneumatic::protocol::ErrorCode> {
+    use pneumatic::protocol::ServerResponse;
+
+    let mut content = Vec::new();
+
+    loop {
+        match client.receive_response().await.unwrap() {
+            ServerResponse::FileChunk(mut chunk) => content.append(&mut chunk),
+            ServerResponse::FileEnd => return Ok(content),
+            ServerResponse::Error(error) => return Err(error),
+            response => panic!("Unexpected response {:?}", response),
+        }
+    }
+}
+
+/// The handshake flags of a peer without an identity key, for tests that
+/// speak the handshake by hand.
+pub const NO_FLAGS: [u8; 1] = [0];
+
+/// The format offer of a peer that prefers and supports only bincode, for
+/// tests that speak the handshake by hand.
+pub const BINCODE_ONLY: [u8; 2] = [0, 0b1];
+
+/// A valid X25519 public key, for tests that speak the handshake by hand.
+pub fn public_key() -> Vec<u8> {
+    use ring::{
+        agreement::{EphemeralPrivateKey, X25519},
+        rand::SystemRandom,
+    };
+
+    let private_key = EphemeralPrivateKey::generate(&X25519, &SystemRandom::new()).unwrap();
+    private_key.compute_public_key().unwrap().as_ref().to_vec()
+}
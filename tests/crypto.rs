@@ -0,0 +1,309 @@
+mod common;
+
+use pneumatic::{
+    clock::MockClock,
+    config::ConnectionConfig,
+    crypto::{expand_key, EncryptedStream, StreamError, FRAME_FORMAT_VERSION, FRAME_HEADER_LEN},
+};
+use ring::hkdf::{Prk, Salt, HKDF_SHA256};
+use std::{sync::Arc, time::Duration};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+fn prk() -> Prk {
+    Salt::new(HKDF_SHA256, b"salt").extract(b"shared secret")
+}
+
+#[test]
+fn key_context_separates_derived_keys() {
+    let default_key = expand_key(prk(), b"");
+    let service_a = expand_key(prk(), b"service-a");
+    let service_b = expand_key(prk(), b"service-b");
+
+    assert_ne!(service_a, service_b);
+    assert_ne!(default_key, service_a);
+    assert_eq!(service_a, expand_key(prk(), b"service-a"));
+}
+
+#[tokio::test]
+async fn max_plaintext_frame_round_trips() {
+    let config = ConnectionConfig {
+        max_frame_size: Some(1024),
+        ..ConnectionConfig::default()
+    };
+    let (mut sender, mut receiver) = common::encrypted_pair(&config).await;
+
+    assert_eq!(sender.frame_overhead(), 16 + FRAME_HEADER_LEN);
+    let max_plaintext = sender.max_plaintext();
+    assert_eq!(max_plaintext, 1024 - sender.frame_overhead());
+
+    let mut too_large = vec![7u8; max_plaintext + 1];
+    let result = sender.send_buffer(&mut too_large).await;
+    assert!(matches!(
+        result,
+        Err(StreamError::FrameTooLarge { size, max }) if size == max_plaintext + 1 && max == max_plaintext
+    ));
+
+    let mut largest = vec![7u8; max_plaintext];
+    sender.send_buffer(&mut largest).await.unwrap();
+    sender.flush().await.unwrap();
+
+    let mut buffer = Vec::new();
+    let received = receiver.receive_buffer(&mut buffer).await.unwrap();
+    assert_eq!(received, &vec![7u8; max_plaintext][..]);
+}
+
+#[tokio::test]
+async fn tiny_max_frame_size_is_raised_to_minimum() {
+    let config = ConnectionConfig {
+        max_frame_size: Some(0),
+        ..ConnectionConfig::default()
+    };
+    assert_eq!(config.get_max_frame_size(), 256);
+
+    let (mut sender, mut receiver) = common::encrypted_pair(&config).await;
+    assert_eq!(sender.max_plaintext(), 256 - sender.frame_overhead());
+
+    sender.send(&42u32).await.unwrap();
+    sender.flush().await.unwrap();
+
+    let mut buffer = Vec::new();
+    let received: u32 = receiver.receive(&mut buffer).await.unwrap();
+    assert_eq!(received, 42);
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn split_halves_send_and_receive_concurrently() {
+    const MESSAGES: u32 = 100;
+
+    let (left, right) = common::encrypted_pair(&ConnectionConfig::default()).await;
+    let (mut left_reader, mut left_writer) = left.split();
+    let (mut right_reader, mut right_writer) = right.split();
+
+    let left_send = tokio::spawn(async move {
+        for i in 0..MESSAGES {
+            left_writer.send(&i).await.unwrap();
+        }
+        left_writer.flush().await.unwrap();
+    });
+
+    let right_send = tokio::spawn(async move {
+        for i in 0..MESSAGES {
+            right_writer.send(&(i * 2)).await.unwrap();
+        }
+        right_writer.flush().await.unwrap();
+    });
+
+    let left_receive = tokio::spawn(async move {
+        let mut buffer = Vec::new();
+        for i in 0..MESSAGES {
+            let received: u32 = left_reader.receive(&mut buffer).await.unwrap();
+            assert_eq!(received, i * 2);
+        }
+    });
+
+    let right_receive = tokio::spawn(async move {
+        let mut buffer = Vec::new();
+        for i in 0..MESSAGES {
+            let received: u32 = right_reader.receive(&mut buffer).await.unwrap();
+            assert_eq!(received, i);
+        }
+    });
+
+    let (a, b, c, d) = futures::join!(left_send, right_send, left_receive, right_receive);
+    a.unwrap();
+    b.unwrap();
+    c.unwrap();
+    d.unwrap();
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn receive_buffer_shrinks_after_large_frame() {
+    let (mut sender, mut receiver) = common::encrypted_pair(&ConnectionConfig::default()).await;
+
+    let send = tokio::spawn(async move {
+        let mut huge = vec![1u8; 4 * 1024 * 1024];
+        sender.send_buffer(&mut huge).await.unwrap();
+
+        for _ in 0..100 {
+            let mut tiny = vec![2u8; 16];
+            sender.send_buffer(&mut tiny).await.unwrap();
+        }
+        sender.flush().await.unwrap();
+    });
+
+    let mut buffer = Vec::new();
+
+    receiver.receive_buffer(&mut buffer).await.unwrap();
+    assert!(buffer.capacity() >= 4 * 1024 * 1024);
+
+    for _ in 0..100 {
+        let received = receiver.receive_buffer(&mut buffer).await.unwrap();
+        assert_eq!(received, &[2u8; 16]);
+    }
+
+    assert!(buffer.capacity() <= 64 * 1024);
+
+    send.await.unwrap();
+}
+
+#[tokio::test]
+async fn unknown_frame_version_is_rejected() {
+    let (stream, mut peer) = common::tcp_pair().await;
+    let config = ConnectionConfig::default();
+
+    // The peer only needs to get through the handshake; an unknown version is
+    // rejected before the frame is decrypted.
+    let peer = async move {
+        let mut received = [0u8; 67];
+        peer.write_all(&common::NO_FLAGS).await.unwrap();
+        peer.write_all(&common::public_key()).await.unwrap();
+        peer.write_all(&[0u8; 32]).await.unwrap();
+        peer.write_all(&common::BINCODE_ONLY).await.unwrap();
+        peer.read_exact(&mut received).await.unwrap();
+
+        peer.write_all(&32u32.to_be_bytes()).await.unwrap();
+        peer.write_all(&[0xFF]).await.unwrap();
+        peer.write_all(&[0u8; 32]).await.unwrap();
+        peer
+    };
+
+    let (stream, _peer) = futures::join!(EncryptedStream::new(stream, &config), peer);
+    let mut stream = stream.unwrap();
+
+    let mut buffer = Vec::new();
+    let result = stream.receive_buffer(&mut buffer).await;
+
+    assert!(matches!(
+        result,
+        Err(StreamError::UnsupportedFrameVersion(0xFF))
+    ));
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn buffered_frames_arrive_after_flush() {
+    const MESSAGES: u32 = 1000;
+
+    let (sender, receiver) = common::encrypted_pair(&ConnectionConfig::default()).await;
+    let (_, mut writer) = sender.split();
+    let (mut reader, _) = receiver.split();
+
+    let send = tokio::spawn(async move {
+        for i in 0..MESSAGES {
+            writer.send(&i).await.unwrap();
+        }
+        writer.flush().await.unwrap();
+        writer
+    });
+
+    let mut buffer = Vec::new();
+    for i in 0..MESSAGES {
+        let received: u32 = reader.receive(&mut buffer).await.unwrap();
+        assert_eq!(received, i);
+    }
+
+    send.await.unwrap();
+}
+
+#[tokio::test]
+async fn receiving_flushes_pending_frames() {
+    let (mut left, mut right) = common::encrypted_pair(&ConnectionConfig::default()).await;
+
+    let right = async move {
+        let mut buffer = Vec::new();
+        let request: u32 = right.receive(&mut buffer).await.unwrap();
+        right.send(&(request + 1)).await.unwrap();
+        right.flush().await.unwrap();
+        right
+    };
+
+    let left = async move {
+        let mut buffer = Vec::new();
+        left.send(&41u32).await.unwrap();
+        // No explicit flush: waiting for the reply must send the request.
+        let reply: u32 = left.receive(&mut buffer).await.unwrap();
+        reply
+    };
+
+    let (reply, _right) = futures::join!(left, right);
+    assert_eq!(reply, 42);
+}
+
+#[tokio::test]
+async fn peer_stopping_mid_frame_times_out() {
+    let (stream, mut peer) = common::tcp_pair().await;
+    let clock = Arc::new(MockClock::new());
+    let config = ConnectionConfig {
+        stall_timeout_ms: Some(100),
+        clock: Some(clock.clone()),
+        ..ConnectionConfig::default()
+    };
+
+    // The peer gets through the handshake and starts a frame, but never
+    // finishes it.
+    let peer = async move {
+        let mut received = [0u8; 67];
+        peer.write_all(&common::NO_FLAGS).await.unwrap();
+        peer.write_all(&common::public_key()).await.unwrap();
+        peer.write_all(&[0u8; 32]).await.unwrap();
+        peer.write_all(&common::BINCODE_ONLY).await.unwrap();
+        peer.read_exact(&mut received).await.unwrap();
+
+        peer.write_all(&1024u32.to_be_bytes()).await.unwrap();
+        peer.write_all(&[FRAME_FORMAT_VERSION]).await.unwrap();
+        peer.write_all(&[0u8; 100]).await.unwrap();
+        peer
+    };
+
+    let (stream, _peer) = futures::join!(EncryptedStream::new(stream, &config), peer);
+    let mut stream = stream.unwrap();
+
+    let receive = tokio::spawn(async move {
+        let mut buffer = Vec::new();
+        stream.receive_buffer(&mut buffer).await.map(|_| ())
+    });
+    let result = common::advance_until_done(&clock, Duration::from_millis(100), receive).await;
+
+    assert!(matches!(result, Err(StreamError::StallTimeout)));
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn peer_that_stops_reading_times_out() {
+    let clock = Arc::new(MockClock::new());
+    let config = ConnectionConfig {
+        stall_timeout_ms: Some(200),
+        clock: Some(clock.clone()),
+        ..ConnectionConfig::default()
+    };
+    let (mut sender, _receiver) = common::encrypted_pair(&config).await;
+
+    // Keep sending until the socket buffers fill up and writes stop making
+    // progress.
+    let send = tokio::spawn(async move {
+        loop {
+            let mut chunk = vec![0u8; 64 * 1024];
+
+            if let Err(error) = sender.send_buffer(&mut chunk).await {
+                break error;
+            }
+        }
+    });
+    let result = common::advance_until_done(&clock, Duration::from_millis(200), send).await;
+
+    assert!(matches!(result, StreamError::StallTimeout));
+}
+
+#[tokio::test]
+async fn absurd_collection_length_is_rejected() {
+    let (mut sender, mut receiver) = common::encrypted_pair(&ConnectionConfig::default()).await;
+
+    // A Vec<u64> whose length prefix claims far more elements than follow.
+    let mut message = u64::MAX.to_le_bytes().to_vec();
+    message.extend_from_slice(&[0u8; 16]);
+    sender.send_buffer(&mut message).await.unwrap();
+    sender.flush().await.unwrap();
+
+    let mut buffer = Vec::new();
+    let result = receiver.receive::<Vec<u64>>(&mut buffer).await;
+
+    assert!(matches!(result, Err(StreamError::Serialization(_))));
+}
@@ -0,0 +1,486 @@
+mod common;
+
+use async_trait::async_trait;
+use futures::StreamExt;
+use pneumatic::{
+    clock::MockClock,
+    config::ServerConfig,
+    transfer::{
+        discover_roots, discovery_stream, monitor_stalls, CachingFileSystem, DirectoryListing,
+        DiscoveryCache, DiscoveryError, DiscoveryMessage, DiscoveryProgress, FileMetadata,
+        FileSystem, RootedDiscoveryMessage, StdFilesystem,
+    },
+};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime},
+};
+use tokio::{sync::Semaphore, time::timeout};
+
+#[tokio::test(threaded_scheduler)]
+async fn discovery_stream_yields_every_file() {
+    let root = common::temp_dir("discovery-stream");
+    fs::create_dir_all(root.join("sub").join("deeper")).unwrap();
+    fs::write(root.join("a.txt"), b"a").unwrap();
+    fs::write(root.join("sub").join("b.txt"), b"bb").unwrap();
+    fs::write(root.join("sub").join("deeper").join("c.txt"), b"ccc").unwrap();
+
+    let fs = Arc::new(StdFilesystem::new(&root));
+    let (sender, receiver) = tokio::sync::mpsc::channel(16);
+
+    let discover = tokio::spawn(fs.discover_files_recursively(root.clone(), sender));
+
+    let mut paths: Vec<PathBuf> = discovery_stream(receiver)
+        .map(|file| file.relative_path)
+        .collect()
+        .await;
+    paths.sort();
+
+    discover.await.unwrap().unwrap();
+
+    assert_eq!(
+        paths,
+        vec![
+            PathBuf::from("a.txt"),
+            PathBuf::from("sub").join("b.txt"),
+            PathBuf::from("sub").join("deeper").join("c.txt"),
+        ]
+    );
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn discovery_hashes_file_contents() {
+    let root = common::temp_dir("discovery-hashing");
+    fs::create_dir_all(root.join("sub")).unwrap();
+    fs::write(root.join("empty.txt"), b"").unwrap();
+    fs::write(root.join("sub").join("large.bin"), vec![42u8; 300_000]).unwrap();
+
+    let config = ServerConfig {
+        hash_during_discovery: Some(true),
+        ..ServerConfig::default()
+    };
+
+    let files = common::discover_all_with_config(&root, &config).await;
+    assert_eq!(files.len(), 2);
+
+    for file in files {
+        let contents = fs::read(root.join(&file.relative_path)).unwrap();
+        let expected = blake3::hash(&contents);
+        assert_eq!(file.content_hash, Some(*expected.as_bytes()));
+    }
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn discovery_does_not_hash_by_default() {
+    let root = common::temp_dir("discovery-no-hashing");
+    fs::write(root.join("file.txt"), b"contents").unwrap();
+
+    let files = common::discover_all(&root).await;
+
+    assert_eq!(files[0].content_hash, None);
+}
+
+/// Wraps `StdFilesystem` with hooks for testing discovery: counts the
+/// directories that are actually read, holds back reading the ones matching
+/// `blocked` until `release` gets a permit, and fails to read the ones
+/// matching `locked`, like directories without read permission. Permissions
+/// can't be relied on in tests since they don't apply to root.
+struct TestFileSystem {
+    inner: StdFilesystem,
+    reads: AtomicUsize,
+    blocked: Box<dyn Fn(&Path) -> bool + Send + Sync>,
+    release: Semaphore,
+    locked: Box<dyn Fn(&Path) -> bool + Send + Sync>,
+}
+
+impl TestFileSystem {
+    fn new(inner: StdFilesystem) -> Self {
+        TestFileSystem {
+            inner,
+            reads: AtomicUsize::new(0),
+            blocked: Box::new(|_| false),
+            release: Semaphore::new(0),
+            locked: Box::new(|_| false),
+        }
+    }
+
+    fn blocking(self, blocked: impl Fn(&Path) -> bool + Send + Sync + 'static) -> Self {
+        TestFileSystem {
+            blocked: Box::new(blocked),
+            ..self
+        }
+    }
+
+    fn locking(self, locked: impl Fn(&Path) -> bool + Send + Sync + 'static) -> Self {
+        TestFileSystem {
+            locked: Box::new(locked),
+            ..self
+        }
+    }
+}
+
+#[async_trait]
+impl FileSystem for TestFileSystem {
+    type Metadata = std::fs::Metadata;
+
+    fn root(&self) -> &Path {
+        self.inner.root()
+    }
+
+    fn convert_metadata(&self, path: &Path, metadata: Self::Metadata) -> FileMetadata {
+        self.inner.convert_metadata(path, metadata)
+    }
+
+    fn concurrency(&self) -> u32 {
+        self.inner.concurrency()
+    }
+
+    async fn read_directory(&self, path: &Path) -> Result<DirectoryListing, DiscoveryError> {
+        if (self.blocked)(path) {
+            // The permit goes back when dropped, so one lets every blocked
+            // read through.
+            drop(self.release.acquire().await);
+        }
+
+        if (self.locked)(path) {
+            let error = std::io::Error::from(std::io::ErrorKind::PermissionDenied);
+            return Err(DiscoveryError::io(path)(error));
+        }
+
+        self.reads.fetch_add(1, Ordering::SeqCst);
+        self.inner.read_directory(path).await
+    }
+
+    async fn directory_modified_at(
+        &self,
+        path: &Path,
+    ) -> Result<Option<SystemTime>, DiscoveryError> {
+        self.inner.directory_modified_at(path).await
+    }
+
+    async fn file_metadata(&self, path: &Path) -> Result<FileMetadata, DiscoveryError> {
+        self.inner.file_metadata(path).await
+    }
+}
+
+async fn discover_cached(
+    root: &Path,
+    cache: DiscoveryCache,
+) -> (Vec<FileMetadata>, usize, DiscoveryCache) {
+    let counting = TestFileSystem::new(StdFilesystem::new(root));
+    let fs = Arc::new(CachingFileSystem::new(counting, cache));
+    let (sender, receiver) = tokio::sync::mpsc::channel(16);
+
+    let discover = tokio::spawn(
+        fs.clone()
+            .discover_files_recursively(root.to_owned(), sender),
+    );
+
+    let mut files: Vec<FileMetadata> = discovery_stream(receiver).collect().await;
+    files.sort_by(|a, b| a.relative_path.cmp(&b.relative_path));
+
+    discover.await.unwrap().unwrap();
+
+    let fs = Arc::try_unwrap(fs).ok().unwrap();
+    let cache = fs.cache();
+    let reads = fs.into_inner().reads.into_inner();
+
+    (files, reads, cache)
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn unchanged_tree_is_discovered_from_cache() {
+    let root = common::temp_dir("discovery-cache");
+    fs::create_dir_all(root.join("sub").join("deeper")).unwrap();
+    fs::create_dir_all(root.join("empty")).unwrap();
+    fs::write(root.join("a.txt"), b"a").unwrap();
+    fs::write(root.join("sub").join("b.txt"), b"bb").unwrap();
+    fs::write(root.join("sub").join("deeper").join("c.txt"), b"ccc").unwrap();
+
+    let cache_path = common::temp_dir("discovery-cache-file").join("cache.bin");
+
+    let (first, first_reads, cache) =
+        discover_cached(&root, DiscoveryCache::load(&cache_path).unwrap()).await;
+    assert_eq!(first_reads, 4);
+    assert_eq!(cache.len(), 4);
+    cache.save(&cache_path).unwrap();
+
+    let (second, second_reads, _) =
+        discover_cached(&root, DiscoveryCache::load(&cache_path).unwrap()).await;
+    assert_eq!(second_reads, 0);
+    assert_eq!(second, first);
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn modified_directory_is_read_again() {
+    let root = common::temp_dir("discovery-cache-invalidation");
+    fs::create_dir_all(root.join("sub")).unwrap();
+    fs::write(root.join("sub").join("b.txt"), b"bb").unwrap();
+
+    let (_, _, cache) = discover_cached(&root, DiscoveryCache::default()).await;
+
+    // Make sure the directory's mtime actually changes on filesystems with
+    // coarse timestamps.
+    std::thread::sleep(std::time::Duration::from_millis(1100));
+    fs::write(root.join("sub").join("new.txt"), b"new").unwrap();
+
+    let (files, reads, _) = discover_cached(&root, cache).await;
+
+    assert_eq!(reads, 1);
+    assert!(files
+        .iter()
+        .any(|file| file.relative_path == Path::new("sub").join("new.txt")));
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn file_modified_in_place_is_read_again() {
+    let root = common::temp_dir("discovery-cache-modified-file");
+    fs::create_dir_all(root.join("sub")).unwrap();
+    fs::write(root.join("sub").join("b.txt"), b"bb").unwrap();
+    fs::write(root.join("sub").join("c.txt"), b"cc").unwrap();
+
+    let (_, _, cache) = discover_cached(&root, DiscoveryCache::default()).await;
+
+    // Doesn't add, remove or rename anything, so the directory's modification
+    // time stays the same.
+    fs::write(root.join("sub").join("b.txt"), b"bbbb").unwrap();
+
+    let (files, reads, _) = discover_cached(&root, cache).await;
+
+    assert_eq!(reads, 1);
+    let sizes: Vec<u64> = files.iter().map(|file| file.uncompressed_size).collect();
+    assert_eq!(sizes, vec![4, 2]);
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn discovery_concurrency_limits_workers() {
+    let root = common::temp_dir("discovery-concurrency");
+    for i in 0..8 {
+        fs::create_dir_all(root.join(i.to_string())).unwrap();
+        fs::write(root.join(i.to_string()).join("file.txt"), b"x").unwrap();
+    }
+
+    let config = ServerConfig {
+        discovery_concurrency: Some(3),
+        transfer_concurrency: Some(8),
+        ..ServerConfig::default()
+    };
+
+    // Every directory but the root stays busy until released.
+    let unblocked = root.clone();
+    let fs = Arc::new(
+        TestFileSystem::new(StdFilesystem::from_config(&root, &config))
+            .blocking(move |path| path != unblocked),
+    );
+    let progress = Arc::new(DiscoveryProgress::new());
+    let (sender, receiver) = tokio::sync::mpsc::channel(16);
+
+    let discover = tokio::spawn(fs.clone().discover_with_progress(
+        root.clone(),
+        sender,
+        progress.clone(),
+    ));
+
+    timeout(Duration::from_secs(5), async {
+        while progress.snapshot().active_workers < 3 {
+            tokio::task::yield_now().await
+        }
+    })
+    .await
+    .expect("workers didn't start");
+
+    let snapshot = progress.snapshot();
+    assert_eq!(snapshot.active_workers, 3);
+    assert_eq!(snapshot.queue_depth, 5);
+
+    fs.release.add_permits(1);
+
+    let files: Vec<FileMetadata> = discovery_stream(receiver).collect().await;
+    assert_eq!(files.len(), 8);
+    discover.await.unwrap().unwrap();
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn blocked_directory_triggers_stall_warning() {
+    let root = common::temp_dir("discovery-stall");
+    for directory in &["a", "b", "c", "blocked"] {
+        fs::create_dir_all(root.join(directory)).unwrap();
+        fs::write(root.join(directory).join("file.txt"), b"x").unwrap();
+    }
+
+    let fs = Arc::new(
+        TestFileSystem::new(StdFilesystem::new(&root)).blocking(|path| path.ends_with("blocked")),
+    );
+    let clock = Arc::new(MockClock::new());
+    let progress = Arc::new(DiscoveryProgress::with_clock(clock.clone()));
+    let (sender, mut receiver) = tokio::sync::mpsc::channel(16);
+    let (warnings_sender, mut warnings) = tokio::sync::mpsc::unbounded_channel();
+
+    let monitor = tokio::spawn(monitor_stalls(
+        progress.clone(),
+        Duration::from_millis(100),
+        move |snapshot| warnings_sender.send(snapshot).unwrap(),
+    ));
+    let discover = tokio::spawn(fs.clone().discover_with_progress(
+        root.clone(),
+        sender,
+        progress.clone(),
+    ));
+
+    // The other directories are discovered while one is stuck.
+    let mut paths = Vec::new();
+    while paths.len() < 3 {
+        match receiver.recv().await.unwrap() {
+            DiscoveryMessage::Files(files) => {
+                paths.extend(files.into_iter().map(|file| file.relative_path))
+            }
+        }
+    }
+    assert!(!paths.iter().any(|path| path.starts_with("blocked")));
+
+    // Time only starts passing once everything but the blocked directory is
+    // done, so the warning can't see a directory that is about to complete.
+    timeout(Duration::from_secs(5), async {
+        while progress.snapshot().completed_directories < 4 {
+            tokio::task::yield_now().await
+        }
+    })
+    .await
+    .expect("directories weren't completed");
+
+    let step = Duration::from_millis(25);
+    let first_warning = tokio::spawn(async move { warnings.recv().await });
+    let warning = common::advance_until_done(&clock, step, first_warning)
+        .await
+        .expect("no stall warning");
+    assert_eq!(warning.active_workers, 1);
+    assert_eq!(warning.completed_directories, 4);
+    assert!(warning.since_last_completion >= Duration::from_millis(100));
+
+    fs.release.add_permits(1);
+
+    let rest: Vec<FileMetadata> = discovery_stream(receiver).collect().await;
+    assert_eq!(rest.len(), 1);
+
+    discover.await.unwrap().unwrap();
+    common::advance_until_done(&clock, step, monitor).await;
+    assert!(progress.snapshot().finished);
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn unreadable_directory_is_an_io_error() {
+    let root = common::temp_dir("discovery-unreadable");
+    fs::create_dir_all(root.join("locked")).unwrap();
+    fs::create_dir_all(root.join("open")).unwrap();
+    fs::write(root.join("open").join("file.txt"), b"x").unwrap();
+
+    let fs = Arc::new(
+        TestFileSystem::new(StdFilesystem::new(&root)).locking(|path| path.ends_with("locked")),
+    );
+    let (sender, receiver) = tokio::sync::mpsc::channel(16);
+
+    let discover = tokio::spawn(fs.discover_files_recursively(root.clone(), sender));
+    let _files: Vec<FileMetadata> = discovery_stream(receiver).collect().await;
+
+    // The other workers must stop too, rather than wait for the failed
+    // directory forever.
+    let result = timeout(Duration::from_secs(5), discover)
+        .await
+        .expect("discovery didn't stop after an error")
+        .unwrap();
+
+    match result {
+        Err(DiscoveryError::Io { path, source }) => {
+            assert_eq!(path, root.join("locked"));
+            assert_eq!(source.kind(), std::io::ErrorKind::PermissionDenied);
+        }
+        result => panic!("Unexpected result {:?}", result),
+    }
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn dropped_receiver_closes_the_channel() {
+    let root = common::temp_dir("discovery-dropped-receiver");
+    fs::write(root.join("file.txt"), b"x").unwrap();
+
+    let fs = Arc::new(StdFilesystem::new(&root));
+    let (sender, receiver) = tokio::sync::mpsc::channel(16);
+    drop(receiver);
+
+    let result = fs.discover_files_recursively(root, sender).await;
+
+    assert!(matches!(result, Err(DiscoveryError::ChannelClosed)));
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn multiple_roots_are_merged_into_one_stream() {
+    let photos = common::temp_dir("discovery-roots-photos");
+    fs::create_dir_all(photos.join("2020")).unwrap();
+    fs::write(photos.join("2020").join("beach.jpg"), b"beach").unwrap();
+
+    let music = common::temp_dir("discovery-roots-music");
+    fs::write(music.join("song.flac"), b"song").unwrap();
+    fs::write(music.join("album.flac"), b"album").unwrap();
+
+    let roots = vec![
+        (Arc::new(StdFilesystem::new(&photos)), photos.clone()),
+        (Arc::new(StdFilesystem::new(&music)), music.clone()),
+    ];
+    let (sender, mut receiver) = tokio::sync::mpsc::channel(16);
+
+    let discover = tokio::spawn(discover_roots(roots, sender));
+
+    let mut files = Vec::new();
+    while let Some(RootedDiscoveryMessage { root, message }) = receiver.recv().await {
+        let DiscoveryMessage::Files(batch) = message;
+        files.extend(
+            batch
+                .into_iter()
+                .map(|file| (root.clone(), file.relative_path)),
+        );
+    }
+    files.sort();
+
+    discover.await.unwrap().unwrap();
+
+    let mut expected = vec![
+        (photos.clone(), PathBuf::from("2020").join("beach.jpg")),
+        (music.clone(), PathBuf::from("album.flac")),
+        (music.clone(), PathBuf::from("song.flac")),
+    ];
+    expected.sort();
+
+    assert_eq!(files, expected);
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn consumer_terminates_when_nothing_is_found() {
+    let root = common::temp_dir("discovery-nothing-found");
+
+    let fs = Arc::new(StdFilesystem::new(&root));
+    let (sender, mut receiver) = tokio::sync::mpsc::channel(16);
+
+    let discover = tokio::spawn(fs.discover_files_recursively(root.clone(), sender));
+
+    let consume = async {
+        let mut files = Vec::new();
+
+        while let Some(DiscoveryMessage::Files(mut batch)) = receiver.recv().await {
+            files.append(&mut batch);
+        }
+
+        files
+    };
+
+    let files = timeout(Duration::from_secs(5), consume)
+        .await
+        .expect("consumer loop didn't terminate");
+
+    assert!(files.is_empty());
+    discover.await.unwrap().unwrap();
+}
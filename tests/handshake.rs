@@ -0,0 +1,194 @@
+mod common;
+
+use async_trait::async_trait;
+use pneumatic::{
+    config::ConnectionConfig,
+    crypto::{self, EncryptedStream, HandshakeError, ResumptionSecrets},
+};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+fn short_timeout() -> ConnectionConfig {
+    ConnectionConfig {
+        handshake_timeout_ms: Some(100),
+        ..ConnectionConfig::default()
+    }
+}
+
+#[tokio::test]
+async fn silent_peer_times_out_during_salt_exchange() {
+    let (stream, mut peer) = common::tcp_pair().await;
+
+    // The peer completes the key exchange and then never sends its salt.
+    peer.write_all(&common::NO_FLAGS).await.unwrap();
+    peer.write_all(&common::public_key()).await.unwrap();
+
+    let result = EncryptedStream::new(stream, &short_timeout()).await;
+
+    assert!(matches!(result, Err(HandshakeError::Timeout)));
+    drop(peer);
+}
+
+#[tokio::test]
+async fn truncated_salt_is_a_short_read() {
+    let (stream, mut peer) = common::tcp_pair().await;
+
+    // The peer sends only half of its salt and then closes the connection.
+    // It reads everything we send first, so that closing it sends a FIN
+    // rather than a reset.
+    let peer = async move {
+        let mut received = [0u8; 65];
+        peer.write_all(&common::NO_FLAGS).await.unwrap();
+        peer.write_all(&common::public_key()).await.unwrap();
+        peer.write_all(&[0u8; 16]).await.unwrap();
+        peer.read_exact(&mut received).await.unwrap();
+    };
+
+    let config = short_timeout();
+    let (result, _) = futures::join!(EncryptedStream::new(stream, &config), peer);
+
+    assert!(matches!(result, Err(HandshakeError::ShortRead)));
+}
+
+fn pinned(identity_key: &[u8], peer_identity_key: &[u8]) -> ConnectionConfig {
+    ConnectionConfig {
+        identity_key: Some(identity_key.to_vec()),
+        pinned_peer_key: Some(crypto::identity_public_key(peer_identity_key).unwrap()),
+        ..short_timeout()
+    }
+}
+
+#[tokio::test]
+async fn matching_pinned_keys_complete_the_handshake() {
+    let (left, right) = common::tcp_pair().await;
+    let left_identity = crypto::generate_identity_key();
+    let right_identity = crypto::generate_identity_key();
+    let left_config = pinned(&left_identity, &right_identity);
+    let right_config = pinned(&right_identity, &left_identity);
+
+    let (left, right) = futures::join!(
+        EncryptedStream::new(left, &left_config),
+        EncryptedStream::new(right, &right_config)
+    );
+    let (mut left, mut right) = (left.unwrap(), right.unwrap());
+
+    let mut buffer = Vec::new();
+    left.send(&42u32).await.unwrap();
+    left.flush().await.unwrap();
+    let received: u32 = right.receive(&mut buffer).await.unwrap();
+    assert_eq!(received, 42);
+}
+
+#[tokio::test]
+async fn mismatched_pinned_key_is_rejected() {
+    let (left, right) = common::tcp_pair().await;
+    let left_identity = crypto::generate_identity_key();
+    let right_identity = crypto::generate_identity_key();
+    let impostor_identity = crypto::generate_identity_key();
+    // The left side expects someone other than the peer it's talking to.
+    let left_config = pinned(&left_identity, &impostor_identity);
+    let right_config = pinned(&right_identity, &left_identity);
+
+    let (left, _right) = futures::join!(
+        EncryptedStream::new(left, &left_config),
+        EncryptedStream::new(right, &right_config)
+    );
+
+    assert!(matches!(left, Err(HandshakeError::PeerKeyMismatch)));
+}
+
+#[tokio::test]
+async fn identity_on_one_side_only_is_rejected() {
+    let (left, right) = common::tcp_pair().await;
+    let left_config = ConnectionConfig {
+        identity_key: Some(crypto::generate_identity_key()),
+        ..short_timeout()
+    };
+    let right_config = short_timeout();
+
+    let (left, right) = futures::join!(
+        EncryptedStream::new(left, &left_config),
+        EncryptedStream::new(right, &right_config)
+    );
+
+    assert!(matches!(left, Err(HandshakeError::IdentityModeMismatch)));
+    assert!(matches!(right, Err(HandshakeError::IdentityModeMismatch)));
+}
+
+#[tokio::test]
+async fn pinned_key_without_identity_is_rejected() {
+    let (stream, _peer) = common::tcp_pair().await;
+    let config = ConnectionConfig {
+        pinned_peer_key: Some(vec![0u8; 32]),
+        ..short_timeout()
+    };
+
+    let result = EncryptedStream::new(stream, &config).await;
+
+    assert!(matches!(result, Err(HandshakeError::MissingIdentity)));
+}
+
+/// The handshake flags of a peer that resumes a session.
+const RESUME_FLAGS: [u8; 1] = [0b10];
+
+const KNOWN_SESSION: [u8; 16] = [7; 16];
+
+struct KnownSession;
+
+#[async_trait]
+impl ResumptionSecrets for KnownSession {
+    async fn resumption_secret(&self, session_id: &[u8]) -> Option<Vec<u8>> {
+        if session_id == KNOWN_SESSION {
+            Some(vec![9; 32])
+        } else {
+            None
+        }
+    }
+}
+
+#[tokio::test]
+async fn resumption_skips_the_key_exchange() {
+    let (stream, mut peer) = common::tcp_pair().await;
+
+    // Flags, whether the session is known, a salt and the format offer. No
+    // public key is sent in either direction.
+    let peer = async move {
+        let mut received = [0u8; 36];
+        peer.write_all(&RESUME_FLAGS).await.unwrap();
+        peer.write_all(&KNOWN_SESSION).await.unwrap();
+        peer.write_all(&[0u8; 32]).await.unwrap();
+        peer.write_all(&common::BINCODE_ONLY).await.unwrap();
+        peer.read_exact(&mut received).await.unwrap();
+        (peer, received)
+    };
+
+    let config = short_timeout();
+    let (result, (_peer, received)) = futures::join!(
+        EncryptedStream::accept(stream, &config, &KnownSession),
+        peer
+    );
+
+    assert!(result.is_ok());
+    assert_eq!(received[1], 1);
+}
+
+#[tokio::test]
+async fn unknown_session_is_not_resumed() {
+    let (stream, mut peer) = common::tcp_pair().await;
+
+    let peer = async move {
+        let mut received = [0u8; 2];
+        peer.write_all(&RESUME_FLAGS).await.unwrap();
+        peer.write_all(&[8u8; 16]).await.unwrap();
+        peer.read_exact(&mut received).await.unwrap();
+        received
+    };
+
+    let config = short_timeout();
+    let (result, received) = futures::join!(
+        EncryptedStream::accept(stream, &config, &KnownSession),
+        peer
+    );
+
+    assert!(matches!(result, Err(HandshakeError::ResumptionRejected)));
+    assert_eq!(received[1], 0);
+}
@@ -0,0 +1,336 @@
+mod common;
+
+use pneumatic::{
+    config::ServerConfig,
+    receiver::{create_directory, resolve_conflict, ConflictPolicy, PartialFile, ReceiveError},
+    transfer::{FileMetadata, TransferPlan},
+};
+use std::{
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+#[tokio::test]
+async fn interrupted_write_leaves_only_partial_file() {
+    let destination = common::temp_dir("interrupted-write");
+    let final_path = destination.join("nested").join("file.bin");
+
+    let mut file = PartialFile::create(&final_path).await.unwrap();
+    let partial_path = file.partial_path().to_owned();
+    file.write(&[1u8; 1024]).await.unwrap();
+
+    // Simulate the transfer being aborted halfway through.
+    drop(file);
+
+    assert!(!final_path.exists());
+    assert!(partial_path.exists());
+    assert_eq!(partial_path.file_name().unwrap(), "file.bin.pneumatic-part");
+}
+
+#[tokio::test]
+async fn commit_moves_file_into_place() {
+    let destination = common::temp_dir("commit");
+    let final_path = destination.join("file.bin");
+
+    let mut file = PartialFile::create(&final_path).await.unwrap();
+    let partial_path = file.partial_path().to_owned();
+    file.write(b"hello ").await.unwrap();
+    file.write(b"world").await.unwrap();
+    file.commit(11, None).await.unwrap();
+
+    assert!(!partial_path.exists());
+    assert_eq!(std::fs::read(&final_path).unwrap(), b"hello world");
+}
+
+#[tokio::test]
+async fn commit_rejects_incomplete_file() {
+    let destination = common::temp_dir("incomplete");
+    let final_path = destination.join("file.bin");
+
+    let mut file = PartialFile::create(&final_path).await.unwrap();
+    let partial_path = file.partial_path().to_owned();
+    file.write(b"hello").await.unwrap();
+
+    let result = file.commit(11, None).await;
+
+    assert!(matches!(result, Err(ReceiveError::SizeMismatch { .. })));
+    assert!(!final_path.exists());
+    assert!(partial_path.exists());
+}
+
+#[tokio::test]
+async fn commit_verifies_hash() {
+    let destination = common::temp_dir("hash");
+    let expected_hash = *blake3::hash(b"hello world").as_bytes();
+
+    // Hashed while writing, and read back at commit.
+    let paths = [
+        destination.join("hashed.bin"),
+        destination.join("read-back.bin"),
+    ];
+    let files = vec![
+        PartialFile::create_hashed(&paths[0]).await.unwrap(),
+        PartialFile::create(&paths[1]).await.unwrap(),
+    ];
+
+    for (mut file, final_path) in files.into_iter().zip(paths.iter()) {
+        file.write(b"hello world").await.unwrap();
+        file.commit(11, Some(expected_hash)).await.unwrap();
+
+        assert_eq!(std::fs::read(final_path).unwrap(), b"hello world");
+    }
+}
+
+#[tokio::test]
+async fn commit_rejects_corrupt_file() {
+    let destination = common::temp_dir("corrupt");
+    let expected_hash = *blake3::hash(b"hello world").as_bytes();
+
+    for hashed in [true, false].iter() {
+        let final_path = destination.join(format!("file-{}.bin", hashed));
+        let mut file = if *hashed {
+            PartialFile::create_hashed(&final_path).await.unwrap()
+        } else {
+            PartialFile::create(&final_path).await.unwrap()
+        };
+        let partial_path = file.partial_path().to_owned();
+        file.write(b"hello wyrld").await.unwrap();
+
+        let result = file.commit(11, Some(expected_hash)).await;
+
+        assert!(matches!(result, Err(ReceiveError::HashMismatch { .. })));
+        assert!(!final_path.exists());
+        assert!(partial_path.exists());
+    }
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn empty_directory_is_recreated() {
+    let source = common::temp_dir("empty-directory-source");
+    std::fs::create_dir_all(source.join("parent").join("empty")).unwrap();
+
+    let plan = TransferPlan::create(
+        common::discover_all(&source).await,
+        &ServerConfig::default(),
+    );
+
+    assert_eq!(plan.directories.len(), 1);
+    assert_eq!(
+        plan.directories[0].relative_path,
+        Path::new("parent").join("empty")
+    );
+
+    let destination = common::temp_dir("empty-directory-destination");
+    for directory in &plan.directories {
+        create_directory(&destination, directory).await.unwrap();
+    }
+
+    assert!(destination.join("parent").join("empty").is_dir());
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn zero_byte_file_is_bundled_and_received() {
+    let source = common::temp_dir("zero-byte-source");
+    std::fs::write(source.join("empty.txt"), b"").unwrap();
+
+    let plan = TransferPlan::create(
+        common::discover_all(&source).await,
+        &ServerConfig::default(),
+    );
+
+    assert!(plan.directories.is_empty());
+    assert_eq!(plan.bundles.len(), 1);
+    assert_eq!(plan.bundles[0].total_size, 0);
+
+    let file = &plan.bundles[0].files[0];
+    assert_eq!(file.relative_path, Path::new("empty.txt"));
+    assert_eq!(file.uncompressed_size, 0);
+
+    let destination = common::temp_dir("zero-byte-destination");
+    let partial = PartialFile::create(destination.join(&file.relative_path))
+        .await
+        .unwrap();
+    let received = partial.commit(file.uncompressed_size, None).await.unwrap();
+
+    assert_eq!(std::fs::metadata(received).unwrap().len(), 0);
+}
+
+/// Creates `existing.txt` in a fresh destination and returns the destination
+/// along with the existing file's modification time.
+fn destination_with_existing_file(name: &str) -> (PathBuf, SystemTime) {
+    let destination = common::temp_dir(name);
+    let path = destination.join("existing.txt");
+    std::fs::write(&path, b"existing").unwrap();
+
+    let modified_at = std::fs::metadata(&path).unwrap().modified().unwrap();
+    (destination, modified_at)
+}
+
+fn tolerance() -> Duration {
+    ServerConfig::default().get_mtime_tolerance()
+}
+
+fn incoming(modified_at: Option<SystemTime>) -> FileMetadata {
+    FileMetadata {
+        modified_at,
+        ..common::file_metadata("existing.txt", 8)
+    }
+}
+
+#[tokio::test]
+async fn missing_destination_is_written_under_any_policy() {
+    let destination = common::temp_dir("conflict-missing");
+    let file = common::file_metadata("new.txt", 3);
+
+    for policy in [
+        ConflictPolicy::Overwrite,
+        ConflictPolicy::Skip,
+        ConflictPolicy::RenameIncoming,
+        ConflictPolicy::NewerWins,
+    ]
+    .iter()
+    {
+        let path = resolve_conflict(&destination, &file, *policy, tolerance())
+            .await
+            .unwrap();
+        assert_eq!(path, Some(destination.join("new.txt")));
+    }
+}
+
+#[tokio::test]
+async fn overwrite_replaces_existing_file() {
+    let (destination, _) = destination_with_existing_file("conflict-overwrite");
+
+    let path = resolve_conflict(
+        &destination,
+        &incoming(None),
+        ConflictPolicy::default(),
+        tolerance(),
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(path, Some(destination.join("existing.txt")));
+}
+
+#[tokio::test]
+async fn skip_keeps_existing_file() {
+    let (destination, _) = destination_with_existing_file("conflict-skip");
+
+    let path = resolve_conflict(
+        &destination,
+        &incoming(None),
+        ConflictPolicy::Skip,
+        tolerance(),
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(path, None);
+}
+
+#[tokio::test]
+async fn rename_incoming_picks_a_free_name() {
+    let (destination, _) = destination_with_existing_file("conflict-rename");
+    std::fs::write(destination.join("existing (1).txt"), b"earlier copy").unwrap();
+
+    let path = resolve_conflict(
+        &destination,
+        &incoming(None),
+        ConflictPolicy::RenameIncoming,
+        tolerance(),
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(path, Some(destination.join("existing (2).txt")));
+    assert_eq!(
+        std::fs::read(destination.join("existing.txt")).unwrap(),
+        b"existing"
+    );
+}
+
+#[tokio::test]
+async fn newer_wins_compares_modification_times() {
+    let (destination, modified_at) = destination_with_existing_file("conflict-newer-wins");
+    let hour = Duration::from_secs(3600);
+
+    let newer = incoming(Some(modified_at + hour));
+    let path = resolve_conflict(&destination, &newer, ConflictPolicy::NewerWins, tolerance())
+        .await
+        .unwrap();
+    assert_eq!(path, Some(destination.join("existing.txt")));
+
+    let older = incoming(Some(modified_at - hour));
+    let path = resolve_conflict(&destination, &older, ConflictPolicy::NewerWins, tolerance())
+        .await
+        .unwrap();
+    assert_eq!(path, None);
+
+    let unknown = incoming(None);
+    let path = resolve_conflict(
+        &destination,
+        &unknown,
+        ConflictPolicy::NewerWins,
+        tolerance(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(path, None);
+}
+
+#[tokio::test]
+async fn newer_wins_ignores_differences_within_tolerance() {
+    let (destination, modified_at) = destination_with_existing_file("conflict-tolerance");
+
+    // E.g. the existing copy was written to FAT, which rounds to two seconds.
+    let barely_newer = incoming(Some(modified_at + tolerance() / 2));
+    let path = resolve_conflict(
+        &destination,
+        &barely_newer,
+        ConflictPolicy::NewerWins,
+        tolerance(),
+    )
+    .await
+    .unwrap();
+
+    assert_eq!(path, None);
+}
+
+#[tokio::test]
+async fn create_with_policy_skips_or_writes_the_file() {
+    let (destination, _) = destination_with_existing_file("conflict-create");
+
+    let skipped = PartialFile::create_with_policy(
+        &destination,
+        &incoming(None),
+        ConflictPolicy::Skip,
+        tolerance(),
+    )
+    .await
+    .unwrap();
+    assert!(skipped.is_none());
+
+    let file = FileMetadata {
+        content_hash: Some(*blake3::hash(b"incoming").as_bytes()),
+        ..incoming(None)
+    };
+    let mut partial = PartialFile::create_with_policy(
+        &destination,
+        &file,
+        ConflictPolicy::RenameIncoming,
+        tolerance(),
+    )
+    .await
+    .unwrap()
+    .unwrap();
+    partial.write(b"incoming").await.unwrap();
+    let path = partial.commit(8, file.content_hash).await.unwrap();
+
+    assert_eq!(path, destination.join("existing (1).txt"));
+    assert_eq!(std::fs::read(&path).unwrap(), b"incoming");
+    assert_eq!(
+        std::fs::read(destination.join("existing.txt")).unwrap(),
+        b"existing"
+    );
+}
@@ -0,0 +1,13 @@
+use std::process::Command;
+
+#[test]
+fn self_test_reports_success() {
+    let output = Command::new(env!("CARGO_BIN_EXE_server"))
+        .arg("--self-test")
+        .output()
+        .unwrap();
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "Self-test failed:\n{}", stdout);
+    assert!(stdout.contains("Self-test passed"), "{}", stdout);
+}
@@ -1,14 +1,31 @@
+mod common;
+
 use pneumatic::server::MockFileSystem;
 use pneumatic::{
     client::Client,
-    protocol::{ClientMessage, Greeting},
-    server::Server,
+    clock::MockClock,
+    config::{ConnectionConfig, ServerConfig},
+    crypto::{EncryptedStream, HandshakeError},
+    protocol::{
+        ClientMessage, CreditUpdate, ErrorCode, Greeting, GreetingResponse, ResumeOffset,
+        ResumeResponse, ResumptionToken, ServerResponse,
+    },
+    server::{DiskFileSystem, FileSystem, Server},
+    transfer::VirtualFile,
 };
 use std::{
     error::Error,
+    io::Cursor,
     net::{Ipv4Addr, SocketAddrV4},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::RwLock,
+    time::timeout,
 };
-use tokio::net::TcpListener;
 
 // TODO: This test is unreliable and prone to race conditions
 #[tokio::test(threaded_scheduler)]
@@ -17,14 +34,15 @@ async fn connect_then_dc() -> Result<(), Box<dyn Error>> {
     let address = SocketAddrV4::new(Ipv4Addr::new(127, 0, 0, 1), 2020);
     let tcp = TcpListener::bind(address).await?;
 
-    let server = Server::start_new(Box::new(fs), tcp);
-    let mut client = Client::connect(address).await;
+    let server = Server::start_new(Box::new(fs), tcp, ServerConfig::default());
+    let mut client = Client::connect(address, &ConnectionConfig::default()).await?;
 
     client
         .send_message(ClientMessage::Greeting(Greeting {
             protocol_version: 1,
+            receive_window: None,
         }))
-        .await;
+        .await?;
 
     let server_reader = server.read().await;
     assert_eq!(server_reader.sessions.len(), 1);
@@ -33,3 +51,880 @@ async fn connect_then_dc() -> Result<(), Box<dyn Error>> {
 
     Ok(())
 }
+
+#[tokio::test(threaded_scheduler)]
+async fn mock_file_system_has_no_files() -> Result<(), Box<dyn Error>> {
+    let (tcp, address) = common::bind_loopback().await;
+    let _server = Server::start_new(
+        Box::new(MockFileSystem::new()),
+        tcp,
+        ServerConfig::default(),
+    );
+    let mut client = Client::connect(address, &ConnectionConfig::default()).await?;
+
+    assert_eq!(
+        common::download(&mut client, "anything.txt").await,
+        Err(ErrorCode::NotFound)
+    );
+
+    Ok(())
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn denied_paths_are_hidden_and_refused() -> Result<(), Box<dyn Error>> {
+    let root = common::temp_dir("path-filter");
+    std::fs::write(root.join("image.iso"), b"disk image")?;
+    std::fs::write(root.join("secret.key"), b"hunter2")?;
+    std::fs::write(root.join("notes.txt"), b"notes")?;
+
+    let config = ServerConfig {
+        allowed_paths: vec!["*.iso".to_owned(), "*.key".to_owned()],
+        denied_paths: vec!["*.key".to_owned()],
+        ..ServerConfig::default()
+    };
+
+    let fs = DiskFileSystem::new(&root, &config)?;
+    let (tcp, address) = common::bind_loopback().await;
+    let _server = Server::start_new(Box::new(fs), tcp, config);
+    let mut client = Client::connect(address, &ConnectionConfig::default()).await?;
+
+    client.send_message(ClientMessage::ListFiles).await?;
+    match client.receive_response().await? {
+        ServerResponse::FileList(files) => assert_eq!(files, vec![PathBuf::from("image.iso")]),
+        response => panic!("Unexpected response {:?}", response),
+    }
+
+    assert_eq!(
+        common::download(&mut client, "secret.key").await,
+        Err(ErrorCode::PermissionDenied)
+    );
+    assert_eq!(
+        common::download(&mut client, "notes.txt").await,
+        Err(ErrorCode::PermissionDenied)
+    );
+    assert_eq!(
+        common::download(&mut client, "image.iso").await,
+        Ok(b"disk image".to_vec())
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn denied_paths_ignore_case() -> Result<(), Box<dyn Error>> {
+    let root = common::temp_dir("path-filter-case");
+    std::fs::write(root.join("secret.KEY"), b"hunter2")?;
+
+    let config = ServerConfig {
+        denied_paths: vec!["*.key".to_owned()],
+        ..ServerConfig::default()
+    };
+    let fs = DiskFileSystem::new(&root, &config)?;
+
+    assert_eq!(
+        fs.open_file(Path::new("secret.KEY")).err(),
+        Some(ErrorCode::PermissionDenied)
+    );
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn symlinks_are_only_served_within_root() -> Result<(), Box<dyn Error>> {
+    use std::os::unix::fs::symlink;
+
+    let outside = common::temp_dir("symlink-outside");
+    std::fs::write(outside.join("passwd"), b"root:x:0:0")?;
+
+    let root = common::temp_dir("symlink-root");
+    std::fs::write(root.join("notes.txt"), b"notes")?;
+    std::fs::write(root.join("secret.key"), b"hunter2")?;
+    symlink(outside.join("passwd"), root.join("escape"))?;
+    symlink(root.join("secret.key"), root.join("innocent.txt"))?;
+    symlink(root.join("notes.txt"), root.join("alias.txt"))?;
+
+    let config = ServerConfig {
+        denied_paths: vec!["*.key".to_owned()],
+        ..ServerConfig::default()
+    };
+    let fs = DiskFileSystem::new(&root, &config)?;
+
+    for path in &["escape", "innocent.txt"] {
+        assert_eq!(
+            fs.open_file(Path::new(path)).err(),
+            Some(ErrorCode::PermissionDenied)
+        );
+    }
+    assert!(fs.open_file(Path::new("alias.txt")).is_ok());
+
+    let mut files = Vec::new();
+    fs.list_files(Path::new(""), &mut files);
+    files.sort();
+    assert_eq!(
+        files,
+        vec![PathBuf::from("alias.txt"), PathBuf::from("notes.txt")]
+    );
+
+    Ok(())
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn disconnect_session_by_address() -> Result<(), Box<dyn Error>> {
+    let (tcp, address) = common::bind_loopback().await;
+    let server = Server::start_new(
+        Box::new(MockFileSystem::new()),
+        tcp,
+        ServerConfig::default(),
+    );
+
+    let mut first = Client::connect(address, &ConnectionConfig::default()).await?;
+    let mut second = Client::connect(address, &ConnectionConfig::default()).await?;
+
+    for client in [&mut first, &mut second].iter_mut() {
+        client
+            .request(Greeting {
+                protocol_version: 1,
+                receive_window: None,
+            })
+            .await?;
+    }
+
+    let sessions = server.read().await.list_sessions();
+    assert_eq!(sessions.len(), 2);
+
+    assert!(server.write().await.disconnect(sessions[0]).await);
+    assert!(!server.write().await.disconnect(sessions[0]).await);
+
+    assert_eq!(server.read().await.list_sessions(), vec![sessions[1]]);
+
+    // Only the client that was kicked should have lost its connection.
+    let mut failed_requests = 0;
+    for client in [&mut first, &mut second].iter_mut() {
+        let response = client
+            .request(Greeting {
+                protocol_version: 1,
+                receive_window: None,
+            })
+            .await;
+
+        if response.is_err() {
+            failed_requests += 1;
+        }
+    }
+    assert_eq!(failed_requests, 1);
+
+    Ok(())
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn cancelled_file_stops_streaming() -> Result<(), Box<dyn Error>> {
+    const FILE_SIZE: usize = 32 * 1024 * 1024;
+
+    let root = common::temp_dir("cancel");
+    std::fs::write(root.join("large.bin"), vec![7u8; FILE_SIZE])?;
+
+    let config = ServerConfig::default();
+    let fs = DiskFileSystem::new(&root, &config)?;
+    let (tcp, address) = common::bind_loopback().await;
+    let _server = Server::start_new(Box::new(fs), tcp, config);
+    let mut client = Client::connect(address, &ConnectionConfig::default()).await?;
+
+    let request_id = client.request_file("large.bin").await?;
+
+    let mut received = 0;
+    for _ in 0..2 {
+        match client.receive_response().await? {
+            ServerResponse::FileChunk(chunk) => received += chunk.len(),
+            response => panic!("Unexpected response {:?}", response),
+        }
+    }
+
+    client.cancel(request_id).await?;
+
+    // Chunks that were already on their way may still arrive, but the stream
+    // must end with a confirmation instead of the rest of the file.
+    loop {
+        match client.receive_response().await? {
+            ServerResponse::FileChunk(chunk) => received += chunk.len(),
+            ServerResponse::Cancelled(id) => {
+                assert_eq!(id, request_id);
+                break;
+            }
+            response => panic!("Unexpected response {:?}", response),
+        }
+    }
+    assert!(received < FILE_SIZE);
+
+    // Nothing from the cancelled file follows the confirmation.
+    client.send_message(ClientMessage::ListFiles).await?;
+    assert!(matches!(
+        client.receive_response().await?,
+        ServerResponse::FileList(_)
+    ));
+
+    client.cancel(request_id).await?;
+    assert!(matches!(
+        client.receive_response().await?,
+        ServerResponse::AlreadyCompleted(id) if id == request_id
+    ));
+
+    Ok(())
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn idle_session_is_reaped() -> Result<(), Box<dyn Error>> {
+    let clock = Arc::new(MockClock::new());
+    let config = ServerConfig {
+        idle_timeout_ms: Some(60_000),
+        ..ServerConfig::default()
+    };
+    let (tcp, address) = common::bind_loopback().await;
+    let server =
+        Server::start_with_clock(Box::new(MockFileSystem::new()), tcp, config, clock.clone());
+
+    let mut client = Client::connect(address, &ConnectionConfig::default()).await?;
+    client
+        .request(Greeting {
+            protocol_version: 1,
+            receive_window: None,
+        })
+        .await?;
+    assert_eq!(server.read().await.list_sessions().len(), 1);
+
+    // Not idle for long enough yet.
+    clock.advance(Duration::from_secs(59));
+    client
+        .request(Greeting {
+            protocol_version: 1,
+            receive_window: None,
+        })
+        .await?;
+
+    // The request above reset the idle time.
+    clock.advance(Duration::from_secs(59));
+    assert_eq!(server.read().await.list_sessions().len(), 1);
+
+    clock.advance(Duration::from_secs(2));
+    assert!(client.receive_response().await.is_err());
+
+    // The session is removed by the accept loop once the session task ends.
+    timeout(Duration::from_secs(5), async {
+        while !server.read().await.list_sessions().is_empty() {
+            tokio::task::yield_now().await
+        }
+    })
+    .await?;
+
+    Ok(())
+}
+
+async fn greet(client: &mut Client) -> Result<ResumptionToken, Box<dyn Error>> {
+    let response = client
+        .request(Greeting {
+            protocol_version: 1,
+            receive_window: None,
+        })
+        .await?;
+
+    match response {
+        GreetingResponse::ProtocolOk {
+            resumption_token, ..
+        } => Ok(resumption_token),
+        response => panic!("Unexpected response {:?}", response),
+    }
+}
+
+async fn wait_for_suspended_sessions(server: &RwLock<Server>, count: usize) {
+    timeout(Duration::from_secs(5), async {
+        while server.read().await.suspended_sessions() != count {
+            tokio::task::yield_now().await
+        }
+    })
+    .await
+    .expect("session wasn't suspended");
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn lost_connection_resumes_session() -> Result<(), Box<dyn Error>> {
+    let contents: Vec<u8> = (0..1_000_000).map(|i| (i % 251) as u8).collect();
+    let root = common::temp_dir("resume");
+    std::fs::write(root.join("large.bin"), &contents)?;
+
+    let config = ServerConfig::default();
+    let fs = DiskFileSystem::new(&root, &config)?;
+    let (tcp, address) = common::bind_loopback().await;
+    let server = Server::start_new(Box::new(fs), tcp, config);
+
+    let mut client = Client::connect(address, &ConnectionConfig::default()).await?;
+    let token = greet(&mut client).await?;
+    let request_id = client.request_file("large.bin").await?;
+
+    let mut received = Vec::new();
+    for _ in 0..3 {
+        match client.receive_response().await? {
+            ServerResponse::FileChunk(mut chunk) => received.append(&mut chunk),
+            response => panic!("Unexpected response {:?}", response),
+        }
+    }
+
+    client.abort();
+    wait_for_suspended_sessions(&server, 1).await;
+
+    let mut client = Client::reconnect(address, &ConnectionConfig::default(), &token).await?;
+    let offset = ResumeOffset {
+        request_id,
+        received: received.len() as u64,
+    };
+    let response = client.resume(token.clone(), Some(offset)).await?;
+    assert_eq!(
+        response,
+        ResumeResponse::Resumed {
+            receive_window: None
+        }
+    );
+
+    // The file continues where the first connection left off.
+    loop {
+        match client.receive_response().await? {
+            ServerResponse::FileChunk(mut chunk) => received.append(&mut chunk),
+            ServerResponse::FileEnd => break,
+            response => panic!("Unexpected response {:?}", response),
+        }
+    }
+    assert_eq!(received, contents);
+
+    // The session is live again, so the token can't be used a second time.
+    let result = Client::reconnect(address, &ConnectionConfig::default(), &token).await;
+    assert!(matches!(result, Err(HandshakeError::ResumptionRejected)));
+
+    Ok(())
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn resumed_session_keeps_flow_control() -> Result<(), Box<dyn Error>> {
+    let contents: Vec<u8> = (0..1_000_000).map(|i| (i % 251) as u8).collect();
+    let root = common::temp_dir("resume-flow-control");
+    std::fs::write(root.join("large.bin"), &contents)?;
+
+    let config = ServerConfig::default();
+    let fs = DiskFileSystem::new(&root, &config)?;
+    let (tcp, address) = common::bind_loopback().await;
+    let server = Server::start_new(Box::new(fs), tcp, config);
+
+    let mut client = Client::connect(address, &ConnectionConfig::default()).await?;
+    let token = match client.greet(Some(4)).await? {
+        GreetingResponse::ProtocolOk {
+            resumption_token, ..
+        } => resumption_token,
+        response => panic!("Unexpected response {:?}", response),
+    };
+    let request_id = client.request_file("large.bin").await?;
+
+    let mut received = Vec::new();
+    for _ in 0..3 {
+        match client.receive_response().await? {
+            ServerResponse::FileChunk(mut chunk) => received.append(&mut chunk),
+            response => panic!("Unexpected response {:?}", response),
+        }
+    }
+
+    client.abort();
+    wait_for_suspended_sessions(&server, 1).await;
+
+    let mut client = Client::reconnect(address, &ConnectionConfig::default(), &token).await?;
+    let offset = ResumeOffset {
+        request_id,
+        received: received.len() as u64,
+    };
+    let response = client.resume(token, Some(offset)).await?;
+    assert_eq!(
+        response,
+        ResumeResponse::Resumed {
+            receive_window: Some(4)
+        }
+    );
+
+    // Far more than a window's worth of chunks is left, so this only finishes
+    // if the client keeps handing out credit.
+    timeout(Duration::from_secs(10), async {
+        loop {
+            match client.receive_response().await.unwrap() {
+                ServerResponse::FileChunk(mut chunk) => received.append(&mut chunk),
+                ServerResponse::FileEnd => break,
+                response => panic!("Unexpected response {:?}", response),
+            }
+        }
+    })
+    .await?;
+    assert_eq!(received, contents);
+
+    Ok(())
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn clean_disconnect_invalidates_resumption_token() -> Result<(), Box<dyn Error>> {
+    let (tcp, address) = common::bind_loopback().await;
+    let server = Server::start_new(
+        Box::new(MockFileSystem::new()),
+        tcp,
+        ServerConfig::default(),
+    );
+
+    let mut client = Client::connect(address, &ConnectionConfig::default()).await?;
+    let token = greet(&mut client).await?;
+    client.send_message(ClientMessage::Disconnect).await?;
+    client.abort();
+
+    timeout(Duration::from_secs(5), async {
+        while !server.read().await.list_sessions().is_empty() {
+            tokio::task::yield_now().await
+        }
+    })
+    .await?;
+    assert_eq!(server.read().await.suspended_sessions(), 0);
+
+    let result = Client::reconnect(address, &ConnectionConfig::default(), &token).await;
+    assert!(matches!(result, Err(HandshakeError::ResumptionRejected)));
+
+    Ok(())
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn resumption_token_expires() -> Result<(), Box<dyn Error>> {
+    let clock = Arc::new(MockClock::new());
+    let config = ServerConfig {
+        resumption_ttl_ms: Some(30_000),
+        ..ServerConfig::default()
+    };
+    let (tcp, address) = common::bind_loopback().await;
+    let server =
+        Server::start_with_clock(Box::new(MockFileSystem::new()), tcp, config, clock.clone());
+
+    let mut client = Client::connect(address, &ConnectionConfig::default()).await?;
+    let token = greet(&mut client).await?;
+    client.abort();
+    wait_for_suspended_sessions(&server, 1).await;
+
+    clock.advance(Duration::from_secs(31));
+    assert_eq!(server.read().await.suspended_sessions(), 0);
+
+    let result = Client::reconnect(address, &ConnectionConfig::default(), &token).await;
+    assert!(matches!(result, Err(HandshakeError::ResumptionRejected)));
+
+    Ok(())
+}
+
+async fn wait_for_sessions_to_end(server: &RwLock<Server>) {
+    timeout(Duration::from_secs(5), async {
+        while !server.read().await.list_sessions().is_empty() {
+            tokio::task::yield_now().await
+        }
+    })
+    .await
+    .expect("session didn't end");
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn session_without_token_is_not_suspended() -> Result<(), Box<dyn Error>> {
+    let (tcp, address) = common::bind_loopback().await;
+    let server = Server::start_new(
+        Box::new(MockFileSystem::new()),
+        tcp,
+        ServerConfig::default(),
+    );
+
+    let client = Client::connect(address, &ConnectionConfig::default()).await?;
+    client.abort();
+
+    wait_for_sessions_to_end(&server).await;
+    assert_eq!(server.read().await.suspended_sessions(), 0);
+
+    Ok(())
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn protocol_error_does_not_suspend_session() -> Result<(), Box<dyn Error>> {
+    let (tcp, address) = common::bind_loopback().await;
+    let server = Server::start_new(
+        Box::new(MockFileSystem::new()),
+        tcp,
+        ServerConfig::default(),
+    );
+
+    let socket = TcpStream::connect(address).await?;
+    let mut stream = EncryptedStream::new(socket, &ConnectionConfig::default()).await?;
+    let greeting = ClientMessage::Greeting(Greeting {
+        protocol_version: 1,
+        receive_window: None,
+    });
+    stream.send(&greeting).await?;
+    let response: GreetingResponse = stream.receive(&mut Vec::new()).await?;
+    assert!(matches!(response, GreetingResponse::ProtocolOk { .. }));
+
+    // Decrypts fine, but isn't a message.
+    stream.send_buffer(&mut vec![0xFF; 8]).await?;
+    stream.flush().await?;
+
+    wait_for_sessions_to_end(&server).await;
+    assert_eq!(server.read().await.suspended_sessions(), 0);
+
+    Ok(())
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn suspended_sessions_are_capped() -> Result<(), Box<dyn Error>> {
+    let config = ServerConfig {
+        max_suspended_sessions: Some(2),
+        ..ServerConfig::default()
+    };
+    let (tcp, address) = common::bind_loopback().await;
+    let server = Server::start_new(Box::new(MockFileSystem::new()), tcp, config);
+
+    let mut tokens = Vec::new();
+    for _ in 0..3 {
+        let mut client = Client::connect(address, &ConnectionConfig::default()).await?;
+        tokens.push(greet(&mut client).await?);
+        client.abort();
+        wait_for_sessions_to_end(&server).await;
+    }
+    assert_eq!(server.read().await.suspended_sessions(), 2);
+
+    // The session that was suspended first made room for the last one.
+    let result = Client::reconnect(address, &ConnectionConfig::default(), &tokens[0]).await;
+    assert!(matches!(result, Err(HandshakeError::ResumptionRejected)));
+
+    let mut client = Client::reconnect(address, &ConnectionConfig::default(), &tokens[2]).await?;
+    let response = client.resume(tokens[2].clone(), None).await?;
+    assert!(matches!(response, ResumeResponse::Resumed { .. }));
+
+    Ok(())
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn forged_token_cannot_resume() -> Result<(), Box<dyn Error>> {
+    let (tcp, address) = common::bind_loopback().await;
+    let server = Server::start_new(
+        Box::new(MockFileSystem::new()),
+        tcp,
+        ServerConfig::default(),
+    );
+
+    let mut client = Client::connect(address, &ConnectionConfig::default()).await?;
+    let token = greet(&mut client).await?;
+    client.abort();
+    wait_for_suspended_sessions(&server, 1).await;
+
+    // The right session, but the wrong secret. The keys derived from it don't
+    // match the server's, so nothing sent on the connection gets through.
+    let mut bytes = bincode::serialize(&token)?;
+    *bytes.last_mut().unwrap() ^= 1;
+    let forged: ResumptionToken = bincode::deserialize(&bytes)?;
+
+    let mut client = Client::reconnect(address, &ConnectionConfig::default(), &forged).await?;
+    assert!(client.resume(forged, None).await.is_err());
+
+    let mut client = Client::reconnect(address, &ConnectionConfig::default(), &token).await?;
+    let response = client.resume(token, None).await?;
+    assert!(matches!(response, ResumeResponse::Resumed { .. }));
+
+    Ok(())
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn virtual_file_is_served_from_reader() -> Result<(), Box<dyn Error>> {
+    let root = common::temp_dir("virtual-file");
+    let fs = DiskFileSystem::new(&root, &ServerConfig::default())?;
+    let (tcp, address) = common::bind_loopback().await;
+    let server = Server::start_new(Box::new(fs), tcp, ServerConfig::default());
+
+    // Spans several chunks, and the reader has more than the declared size.
+    let content: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+    let mut reader = content.clone();
+    reader.extend_from_slice(b"trailing bytes");
+
+    server.write().await.add_virtual_file(VirtualFile::new(
+        "generated/archive.tar",
+        content.len() as u64,
+        Cursor::new(reader),
+    ));
+
+    let mut client = Client::connect(address, &ConnectionConfig::default()).await?;
+
+    client.send_message(ClientMessage::ListFiles).await?;
+    match client.receive_response().await? {
+        ServerResponse::FileList(files) => {
+            assert_eq!(files, vec![PathBuf::from("generated/archive.tar")])
+        }
+        response => panic!("Unexpected response {:?}", response),
+    }
+
+    assert_eq!(
+        common::download(&mut client, "generated/archive.tar").await,
+        Ok(content)
+    );
+
+    Ok(())
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn file_ranges_are_served() -> Result<(), Box<dyn Error>> {
+    let root = common::temp_dir("file-ranges");
+    let content: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+    std::fs::write(root.join("data.bin"), &content)?;
+
+    let fs = DiskFileSystem::new(&root, &ServerConfig::default())?;
+    let (tcp, address) = common::bind_loopback().await;
+    let server = Server::start_new(Box::new(fs), tcp, ServerConfig::default());
+
+    server.write().await.add_virtual_file(VirtualFile::new(
+        "generated/data.bin",
+        content.len() as u64,
+        Cursor::new(content.clone()),
+    ));
+
+    let mut client = Client::connect(address, &ConnectionConfig::default()).await?;
+
+    // Spans several chunks from the middle of the file.
+    client
+        .request_file_range("data.bin", 30_000, 50_000)
+        .await?;
+    assert_eq!(
+        common::receive_file(&mut client).await,
+        Ok(content[30_000..80_000].to_vec())
+    );
+
+    // Cut short at the end of the file.
+    client
+        .request_file_range("data.bin", 90_000, 50_000)
+        .await?;
+    assert_eq!(
+        common::receive_file(&mut client).await,
+        Ok(content[90_000..].to_vec())
+    );
+
+    // A virtual file can only be served once, so it gets a single range.
+    client
+        .request_file_range("generated/data.bin", 30_000, 50_000)
+        .await?;
+    assert_eq!(
+        common::receive_file(&mut client).await,
+        Ok(content[30_000..80_000].to_vec())
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn range_past_end_of_file_is_refused() -> Result<(), Box<dyn Error>> {
+    let root = common::temp_dir("file-range-past-end");
+    std::fs::write(root.join("small.txt"), b"hello")?;
+
+    let fs = DiskFileSystem::new(&root, &ServerConfig::default())?;
+    let (tcp, address) = common::bind_loopback().await;
+    let _server = Server::start_new(Box::new(fs), tcp, ServerConfig::default());
+
+    let mut client = Client::connect(address, &ConnectionConfig::default()).await?;
+
+    // A range starting exactly at the end is empty, not an error.
+    client.request_file_range("small.txt", 5, 10).await?;
+    assert_eq!(common::receive_file(&mut client).await, Ok(Vec::new()));
+
+    client.request_file_range("small.txt", 6, 10).await?;
+    assert_eq!(
+        common::receive_file(&mut client).await,
+        Err(ErrorCode::InvalidRange)
+    );
+
+    Ok(())
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn invalid_range_keeps_virtual_file() -> Result<(), Box<dyn Error>> {
+    let (tcp, address) = common::bind_loopback().await;
+    let server = Server::start_new(
+        Box::new(MockFileSystem::new()),
+        tcp,
+        ServerConfig::default(),
+    );
+
+    server.write().await.add_virtual_file(VirtualFile::new(
+        "generated.txt",
+        5,
+        Cursor::new(b"hello".to_vec()),
+    ));
+
+    let mut client = Client::connect(address, &ConnectionConfig::default()).await?;
+
+    client.request_file_range("generated.txt", 6, 10).await?;
+    assert_eq!(
+        common::receive_file(&mut client).await,
+        Err(ErrorCode::InvalidRange)
+    );
+
+    // The refused request didn't consume the file.
+    assert_eq!(
+        common::download(&mut client, "generated.txt").await,
+        Ok(b"hello".to_vec())
+    );
+
+    Ok(())
+}
+
+/// Receives `count` chunks and returns their total size.
+async fn receive_chunks(client: &mut Client, count: usize) -> usize {
+    let mut received = 0;
+
+    for _ in 0..count {
+        match client.receive_response().await.unwrap() {
+            ServerResponse::FileChunk(chunk) => received += chunk.len(),
+            response => panic!("Unexpected response {:?}", response),
+        }
+    }
+
+    received
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn exhausted_window_blocks_until_credit() -> Result<(), Box<dyn Error>> {
+    const FILE_SIZE: usize = 256 * 1024;
+
+    let root = common::temp_dir("flow-control-window");
+    std::fs::write(root.join("file.bin"), vec![3u8; FILE_SIZE])?;
+
+    let config = ServerConfig::default();
+    let fs = DiskFileSystem::new(&root, &config)?;
+    let (tcp, address) = common::bind_loopback().await;
+    let _server = Server::start_new(Box::new(fs), tcp, config);
+    let mut client = Client::connect(address, &ConnectionConfig::default()).await?;
+
+    // Greeting by hand, so that the client doesn't hand out credit by itself.
+    let response = client
+        .request(Greeting {
+            protocol_version: 1,
+            receive_window: Some(2),
+        })
+        .await?;
+    assert!(matches!(
+        response,
+        GreetingResponse::ProtocolOk {
+            receive_window: Some(2),
+            ..
+        }
+    ));
+
+    client.request_file("file.bin").await?;
+
+    let mut received = receive_chunks(&mut client, 2).await;
+    let silence = timeout(Duration::from_millis(300), client.receive_response()).await;
+    assert!(silence.is_err(), "server sent more than the window");
+
+    client
+        .send_message(CreditUpdate { frames: 1 }.into())
+        .await?;
+    received += receive_chunks(&mut client, 1).await;
+    let silence = timeout(Duration::from_millis(300), client.receive_response()).await;
+    assert!(silence.is_err(), "server sent more than the credit");
+
+    // The rest of the file arrives as credit is handed out, followed by
+    // `FileEnd`, which doesn't need any.
+    while received < FILE_SIZE {
+        client
+            .send_message(CreditUpdate { frames: 1 }.into())
+            .await?;
+        received += receive_chunks(&mut client, 1).await;
+    }
+
+    assert_eq!(received, FILE_SIZE);
+    assert!(matches!(
+        client.receive_response().await?,
+        ServerResponse::FileEnd
+    ));
+
+    Ok(())
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn client_hands_out_credit_while_downloading() -> Result<(), Box<dyn Error>> {
+    let content: Vec<u8> = (0..1_000_000u32).map(|i| (i % 253) as u8).collect();
+
+    let root = common::temp_dir("flow-control-download");
+    std::fs::write(root.join("file.bin"), &content)?;
+
+    let config = ServerConfig::default();
+    let fs = DiskFileSystem::new(&root, &config)?;
+    let (tcp, address) = common::bind_loopback().await;
+    let _server = Server::start_new(Box::new(fs), tcp, config);
+    let mut client = Client::connect(address, &ConnectionConfig::default()).await?;
+
+    let response = client.greet(Some(3)).await?;
+    assert!(matches!(
+        response,
+        GreetingResponse::ProtocolOk {
+            receive_window: Some(3),
+            ..
+        }
+    ));
+
+    let download = common::download(&mut client, "file.bin");
+    assert_eq!(
+        timeout(Duration::from_secs(10), download).await?,
+        Ok(content)
+    );
+
+    Ok(())
+}
+
+/// The value of `name` in Prometheus text output.
+fn metric(text: &str, name: &str) -> u64 {
+    text.lines()
+        .filter(|line| !line.starts_with('#'))
+        .find_map(|line| {
+            let mut parts = line.split(' ');
+            match (parts.next(), parts.next()) {
+                (Some(metric), Some(value)) if metric == name => value.parse().ok(),
+                _ => None,
+            }
+        })
+        .unwrap_or_else(|| panic!("{} is missing from the metrics", name))
+}
+
+#[tokio::test(threaded_scheduler)]
+async fn metrics_count_connections_and_traffic() -> Result<(), Box<dyn Error>> {
+    let (tcp, address) = common::bind_loopback().await;
+    let server = Server::start_new(
+        Box::new(MockFileSystem::new()),
+        tcp,
+        ServerConfig::default(),
+    );
+
+    let before = server.read().await.metrics_text();
+
+    for name in &[
+        "pneumatic_active_sessions",
+        "pneumatic_connections_total",
+        "pneumatic_connection_errors_total",
+        "pneumatic_handshake_failures_total",
+        "pneumatic_bytes_sent_total",
+        "pneumatic_bytes_received_total",
+        "pneumatic_frames_sent_total",
+        "pneumatic_frames_received_total",
+    ] {
+        assert!(before.contains(&format!("# HELP {} ", name)));
+        assert!(before.contains(&format!("# TYPE {} ", name)));
+        assert_eq!(metric(&before, name), 0);
+    }
+
+    let mut client = Client::connect(address, &ConnectionConfig::default()).await?;
+    greet(&mut client).await?;
+
+    let after = server.read().await.metrics_text();
+    assert_eq!(metric(&after, "pneumatic_active_sessions"), 1);
+    assert_eq!(metric(&after, "pneumatic_connections_total"), 1);
+    assert_eq!(metric(&after, "pneumatic_frames_received_total"), 1);
+    assert_eq!(metric(&after, "pneumatic_frames_sent_total"), 1);
+    assert!(metric(&after, "pneumatic_bytes_received_total") > 0);
+    assert!(metric(&after, "pneumatic_bytes_sent_total") > 0);
+
+    Ok(())
+}
@@ -0,0 +1,244 @@
+mod common;
+
+use pneumatic::{
+    config::ServerConfig,
+    transfer::{
+        diff, size_histogram, Compression, FileMetadata, SizeBucket, TransferItem, TransferPlan,
+    },
+};
+use std::{
+    path::PathBuf,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::{Duration, SystemTime},
+};
+
+#[test]
+fn diff_tolerates_small_mtime_differences() {
+    let tolerance = ServerConfig::default().get_mtime_tolerance();
+    let now = SystemTime::now();
+
+    let mut source_file = common::file_metadata("file.txt", 10);
+    source_file.modified_at = Some(now);
+
+    let mut almost_same = common::file_metadata("file.txt", 10);
+    almost_same.modified_at = Some(now - Duration::from_secs(1));
+
+    let mut changed = common::file_metadata("file.txt", 10);
+    changed.modified_at = Some(now - Duration::from_secs(10));
+
+    let source = vec![source_file];
+
+    assert!(diff(&source, &[almost_same], tolerance).is_empty());
+    assert_eq!(diff(&source, &[changed], tolerance).len(), 1);
+}
+
+#[test]
+fn diff_compares_missing_mtimes_exactly() {
+    let tolerance = ServerConfig::default().get_mtime_tolerance();
+
+    let source = vec![common::file_metadata("file.txt", 10)];
+    let mut with_mtime = common::file_metadata("file.txt", 10);
+    with_mtime.modified_at = Some(SystemTime::now());
+
+    assert!(diff(&source, &[common::file_metadata("file.txt", 10)], tolerance).is_empty());
+    assert_eq!(diff(&source, &[with_mtime], tolerance).len(), 1);
+    assert_eq!(diff(&source, &[], tolerance).len(), 1);
+}
+
+#[test]
+fn files_are_planned_as_stored() {
+    let files = vec![
+        common::file_metadata("photo.jpg", 100),
+        common::file_metadata("notes.txt", 100),
+    ];
+
+    let plan = TransferPlan::create(files, &ServerConfig::default());
+
+    for file in &plan.bundles[0].files {
+        assert_eq!(file.compression, Compression::Stored);
+    }
+}
+
+#[test]
+fn effective_created_at_falls_back_to_modified_at() {
+    let created = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+    let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(2000);
+
+    let mut both = common::file_metadata("both.txt", 1);
+    both.created_at = Some(created);
+    both.modified_at = Some(modified);
+    assert_eq!(both.effective_created_at(), Some(created));
+
+    let mut only_modified = common::file_metadata("modified.txt", 1);
+    only_modified.modified_at = Some(modified);
+    assert_eq!(only_modified.effective_created_at(), Some(modified));
+
+    let neither = common::file_metadata("neither.txt", 1);
+    assert_eq!(neither.effective_created_at(), None);
+}
+
+#[tokio::test]
+async fn transfer_concurrency_limits_bundles_in_flight() {
+    let config = ServerConfig {
+        bundle_target_size: Some(100),
+        transfer_concurrency: Some(2),
+        ..ServerConfig::default()
+    };
+
+    let files = (0..6)
+        .map(|i| common::file_metadata(&format!("file{}.txt", i), 100))
+        .collect();
+    let plan = TransferPlan::create(files, &config);
+    assert_eq!(plan.bundles.len(), 6);
+
+    let in_flight = AtomicUsize::new(0);
+    let max_in_flight = AtomicUsize::new(0);
+    let sent = AtomicUsize::new(0);
+
+    let send = |item| {
+        assert!(matches!(item, TransferItem::Bundle(_)));
+
+        async {
+            let current = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            max_in_flight.fetch_max(current, Ordering::SeqCst);
+
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+
+            in_flight.fetch_sub(1, Ordering::SeqCst);
+            sent.fetch_add(1, Ordering::SeqCst);
+            Ok::<(), ()>(())
+        }
+    };
+
+    plan.execute(config.get_transfer_concurrency() as usize, send)
+        .await
+        .unwrap();
+
+    assert_eq!(sent.load(Ordering::SeqCst), 6);
+    assert_eq!(max_in_flight.load(Ordering::SeqCst), 2);
+}
+
+#[test]
+fn plan_order_is_deterministic() {
+    let config = ServerConfig {
+        small_file_threshold_bytes: Some(10),
+        large_file_threshold_bytes: Some(1000),
+        bundle_target_size: Some(20),
+        ..ServerConfig::default()
+    };
+
+    let files = vec![
+        common::file_metadata("d.txt", 5),
+        common::file_metadata("b.txt", 50),
+        common::file_metadata("a.txt", 5),
+        common::file_metadata("c.txt", 50),
+        common::file_metadata("e.txt", 5),
+        common::file_metadata("a/z.txt", 50),
+    ];
+
+    let mut reversed = files.clone();
+    reversed.reverse();
+    let mut rotated = files.clone();
+    rotated.rotate_left(2);
+
+    let plan = TransferPlan::create(files, &config);
+    assert_eq!(plan, TransferPlan::create(reversed, &config));
+    assert_eq!(plan, TransferPlan::create(rotated, &config));
+
+    let paths = |files: &[FileMetadata]| -> Vec<PathBuf> {
+        files
+            .iter()
+            .map(|file| file.relative_path.clone())
+            .collect()
+    };
+
+    assert_eq!(
+        paths(&plan.single_chunk_files),
+        vec![
+            PathBuf::from("a/z.txt"),
+            PathBuf::from("b.txt"),
+            PathBuf::from("c.txt")
+        ]
+    );
+    let bundled: Vec<PathBuf> = plan
+        .bundles
+        .iter()
+        .flat_map(|bundle| paths(&bundle.files))
+        .collect();
+    let mut sorted = bundled.clone();
+    sorted.sort();
+    assert_eq!(bundled.len(), 3);
+    assert_eq!(bundled, sorted);
+}
+
+#[test]
+fn files_are_ordered_by_size_then_path() {
+    let mut files = [
+        common::file_metadata("b.txt", 10),
+        common::file_metadata("c.txt", 5),
+        common::file_metadata("a.txt", 10),
+    ];
+    files.sort();
+
+    let order: Vec<(&str, u64)> = files
+        .iter()
+        .map(|file| (file.relative_path.to_str().unwrap(), file.uncompressed_size))
+        .collect();
+    assert_eq!(order, vec![("c.txt", 5), ("a.txt", 10), ("b.txt", 10)]);
+
+    // Two versions of the same file aren't equal, so they don't compare equal
+    // either.
+    let older = common::file_metadata("a.txt", 10);
+    let newer = FileMetadata {
+        modified_at: Some(SystemTime::now()),
+        ..older.clone()
+    };
+    assert_ne!(older, newer);
+    assert_ne!(older.cmp(&newer), std::cmp::Ordering::Equal);
+    assert_eq!(older.cmp(&older.clone()), std::cmp::Ordering::Equal);
+}
+
+#[test]
+fn size_histogram_buckets_files_by_size() {
+    let mut directory = common::file_metadata("empty", 0);
+    directory.is_directory = true;
+
+    let files = vec![
+        common::file_metadata("empty.txt", 0),
+        common::file_metadata("small.txt", 99),
+        common::file_metadata("boundary.txt", 100),
+        common::file_metadata("medium.txt", 500),
+        common::file_metadata("large.bin", 1000),
+        common::file_metadata("huge.bin", 5000),
+        directory,
+    ];
+
+    let histogram = size_histogram(&files, &[1000, 100]);
+
+    let bucket = |min, max, count, total_bytes| SizeBucket {
+        min,
+        max,
+        count,
+        total_bytes,
+    };
+
+    assert_eq!(
+        histogram.buckets,
+        vec![
+            bucket(0, Some(100), 2, 99),
+            bucket(100, Some(1000), 2, 600),
+            bucket(1000, None, 2, 6000),
+        ]
+    );
+}
+
+#[test]
+fn size_histogram_of_nothing_is_all_zero() {
+    let histogram = size_histogram(&[], &[100]);
+
+    assert_eq!(histogram.buckets.len(), 2);
+    assert!(histogram
+        .buckets
+        .iter()
+        .all(|bucket| bucket.count == 0 && bucket.total_bytes == 0));
+}